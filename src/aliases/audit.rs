@@ -0,0 +1,221 @@
+//! Work out which alias incoming mail was sent to, and spot leaked aliases
+use super::{AliasRecord, Registry};
+use crate::email::{Email, Watermark};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Domain of the duckduckgo aliases
+pub const DUCK_DOMAIN: &str = "duck.com";
+
+/// What was seen in mail sent to an alias
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AliasStats {
+    /// Number of messages
+    pub messages: u64,
+    /// Number of messages flagged as spam by the mail server
+    pub spam: u64,
+    /// Messages per sender address
+    pub senders: BTreeMap<String, u64>,
+    /// Sender of the first message, expected to be the site the alias was given to
+    pub first_sender: Option<String>,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+impl AliasStats {
+    pub fn is_empty(&self) -> bool {
+        self.messages == 0
+    }
+
+    fn observe(&mut self, sender: &str, spam: bool, date: DateTime<Utc>) {
+        self.messages += 1;
+        if spam {
+            self.spam += 1;
+        }
+        *self.senders.entry(sender.to_string()).or_default() += 1;
+        if self.first_sender.is_none() {
+            self.first_sender = Some(sender.to_string());
+        }
+        self.first_seen = Some(self.first_seen.map_or(date, |d| d.min(date)));
+        self.last_seen = Some(self.last_seen.map_or(date, |d| d.max(date)));
+    }
+}
+
+/// When to consider an alias leaked
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LeakPolicy {
+    /// Number of unexpected sender domains tolerated
+    pub tolerated_domains: usize,
+    /// Number of spam messages tolerated
+    pub tolerated_spam: u64,
+}
+
+impl AliasRecord {
+    /// Base domains mail to this alias is expected from: the site it was
+    /// given to, or the sender of the first message when no site is set.
+    pub fn expected_domains(&self) -> Vec<String> {
+        let site = self.site.as_deref().map(|site| {
+            let host = site.split("://").last().unwrap_or(site);
+            host.split(['/', ':']).next().unwrap_or(host).to_string()
+        });
+        let first = || self.stats.first_sender.as_deref().map(domain_of);
+        site.or_else(first)
+            .map(|d| base_domain(&d))
+            .into_iter()
+            .collect()
+    }
+
+    /// Sender domains other than the expected ones.
+    pub fn unexpected_domains(&self) -> Vec<String> {
+        let expected = self.expected_domains();
+        let mut domains: Vec<String> = self
+            .stats
+            .senders
+            .keys()
+            .map(|s| base_domain(&domain_of(s)))
+            .filter(|d| !expected.contains(d))
+            .collect();
+        domains.sort();
+        domains.dedup();
+        domains
+    }
+
+    /// Why the alias looks leaked, empty if it does not.
+    pub fn leak_reasons(&self, policy: &LeakPolicy) -> Vec<String> {
+        let mut reasons = vec![];
+        let unexpected = self.unexpected_domains();
+        if unexpected.len() > policy.tolerated_domains {
+            reasons.push(format!("mail from {}", unexpected.join(", ")));
+        }
+        if self.stats.spam > policy.tolerated_spam {
+            reasons.push(format!("{} spam messages", self.stats.spam));
+        }
+        reasons
+    }
+}
+
+impl Registry {
+    /// Count a message against every alias it was addressed to, recording
+    /// aliases which were not generated through mailsitter.
+    /// Returns the aliases the message was addressed to.
+    pub fn observe(&mut self, email: &Email) -> Vec<String> {
+        let (sender, forwarded_for) = original_sender(email);
+        let mut aliases = alias_recipients(email);
+        if let Some(alias) = forwarded_for {
+            if !aliases.contains(&alias) {
+                aliases.push(alias);
+            }
+        }
+        let Some(sender) = sender else {
+            return aliases;
+        };
+        let spam = looks_like_spam(email);
+        let date = email.date.unwrap_or_else(Utc::now);
+        for alias in &aliases {
+            if self.get(alias).is_none() {
+                let mut record = AliasRecord::new(alias.clone(), "duckduckgo", "");
+                record.created_at = date;
+                record.notes.push("discovered by audit".to_string());
+                self.record(record);
+            }
+            if let Some(record) = self.get_mut(alias) {
+                record.stats.observe(&sender, spam, date);
+            }
+        }
+        aliases
+    }
+
+    /// Count the messages of `account` fetched up to `watermark` and advance
+    /// its audit watermark. Messages an audit running meanwhile already
+    /// counted are skipped. Returns the number of messages counted.
+    pub fn audit(&mut self, account: &str, watermark: Watermark, emails: &[Email]) -> usize {
+        let audited = self.audited.get(account).copied();
+        let counted = |uid: Option<u32>| {
+            audited.is_some_and(|a| uid.is_some_and(|uid| a.covers(watermark.uid_validity, uid)))
+        };
+        let fresh: Vec<&Email> = emails.iter().filter(|e| !counted(e.uid)).collect();
+        for email in &fresh {
+            self.observe(email);
+        }
+        if !counted(Some(watermark.uid)) {
+            self.audited.insert(account.to_string(), watermark);
+        }
+        fresh.len()
+    }
+
+    /// Flag aliases matching the policy, returns the ones newly flagged.
+    pub fn flag_leaks(&mut self, policy: &LeakPolicy) -> Vec<&AliasRecord> {
+        let now = Utc::now();
+        self.aliases
+            .iter_mut()
+            .filter(|a| a.leaked.is_none() && !a.leak_reasons(policy).is_empty())
+            .map(|a| {
+                a.leaked = Some(now);
+                &*a
+            })
+            .collect()
+    }
+}
+
+/// `@duck.com` addresses among the recipients of a message.
+pub fn alias_recipients(email: &Email) -> Vec<String> {
+    let mut aliases: Vec<String> = email
+        .to
+        .iter()
+        .filter(|a| domain_of(a) == DUCK_DOMAIN)
+        .cloned()
+        .collect();
+    aliases.sort();
+    aliases.dedup();
+    aliases
+}
+
+/// Sender of a message, undoing the duckduckgo rewrite of replies
+/// `sender_at_example.com_alias@duck.com`, which also names the alias.
+pub fn original_sender(email: &Email) -> (Option<String>, Option<String>) {
+    let Some(from) = email.from_addr() else {
+        return (None, None);
+    };
+    if let Some(local) = from.strip_suffix(&format!("@{}", DUCK_DOMAIN)) {
+        if let Some((sender, alias)) = local.rsplit_once('_') {
+            if sender.contains("_at_") {
+                return (
+                    Some(sender.replacen("_at_", "@", 1)),
+                    Some(format!("{}@{}", alias, DUCK_DOMAIN)),
+                );
+            }
+        }
+    }
+    (Some(from), None)
+}
+
+/// Whether the mail server marked the message as spam.
+pub fn looks_like_spam(email: &Email) -> bool {
+    email
+        .header("X-Spam-Flag")
+        .any(|v| v.trim().eq_ignore_ascii_case("yes"))
+        || email
+            .header("X-Spam-Status")
+            .chain(email.header("X-Spam"))
+            .any(|v| v.trim().to_lowercase().starts_with("yes"))
+}
+
+fn domain_of(addr: &str) -> String {
+    addr.rsplit_once('@')
+        .map(|(_, d)| d)
+        .unwrap_or(addr)
+        .to_lowercase()
+}
+
+/// Registrable domain of a host by the public suffix list, so `news.shop.example`
+/// and `shop.example` match while `a.co.uk` and `b.co.uk` do not.
+/// Hosts without one, such as IP addresses, are kept whole.
+fn base_domain(domain: &str) -> String {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    match psl::domain_str(&domain) {
+        Some(base) => base.to_string(),
+        None => domain,
+    }
+}
@@ -0,0 +1,142 @@
+//! Module for the local registry of generated aliases
+
+pub mod audit;
+use crate::email::Watermark;
+use crate::Config;
+pub use audit::{AliasStats, LeakPolicy};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// An alias handed out by a provider
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AliasRecord {
+    /// Full address, e.g. `abc@duck.com`
+    pub address: String,
+    /// Provider which generated the alias, e.g. `duckduckgo`
+    pub provider: String,
+    /// Provider account the alias belongs to
+    pub account: String,
+    pub created_at: DateTime<Utc>,
+    /// Short name, e.g. the vendor the alias was given to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Site the alias was used on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<String>,
+    /// Mail received by the alias, updated by audits
+    #[serde(default, skip_serializing_if = "AliasStats::is_empty")]
+    pub stats: AliasStats,
+    /// When an audit flagged the alias as leaked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leaked: Option<DateTime<Utc>>,
+}
+
+/// What to record with a newly generated alias, see [`crate::providers::AliasProvider::generate_recorded`]
+#[derive(Debug, Clone, Default)]
+pub struct AliasDetails {
+    pub label: Option<String>,
+    pub site: Option<String>,
+    pub notes: Vec<String>,
+}
+
+impl AliasRecord {
+    pub fn new(address: String, provider: &str, account: &str) -> Self {
+        Self {
+            address,
+            provider: provider.to_string(),
+            account: account.to_string(),
+            created_at: Utc::now(),
+            label: None,
+            site: None,
+            notes: vec![],
+            stats: AliasStats::default(),
+            leaked: None,
+        }
+    }
+
+    /// Whether the address, or its part before `@`, is `address`.
+    pub fn is(&self, address: &str) -> bool {
+        self.address.eq_ignore_ascii_case(address)
+            || self
+                .address
+                .split('@')
+                .next()
+                .is_some_and(|local| local.eq_ignore_ascii_case(address))
+    }
+
+    /// Case insensitive match of `query` against every text field.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        [
+            Some(&self.address),
+            Some(&self.provider),
+            Some(&self.account),
+            self.label.as_ref(),
+            self.site.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.notes.iter())
+        .any(|field| field.to_lowercase().contains(&query))
+    }
+}
+
+/// Registry file, stored next to the config file
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Registry {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub aliases: Vec<AliasRecord>,
+    /// Last message audited per email account
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub audited: BTreeMap<String, Watermark>,
+}
+
+impl Config for Registry {}
+
+impl Registry {
+    /// Append a newly generated alias.
+    pub fn record(&mut self, alias: AliasRecord) {
+        self.aliases.push(alias);
+    }
+
+    pub fn get(&self, address: &str) -> Option<&AliasRecord> {
+        self.aliases.iter().find(|a| a.is(address))
+    }
+
+    pub fn get_mut(&mut self, address: &str) -> Option<&mut AliasRecord> {
+        self.aliases.iter_mut().find(|a| a.is(address))
+    }
+
+    pub fn search<'a>(&'a self, query: &'a str) -> impl Iterator<Item = &'a AliasRecord> {
+        self.aliases.iter().filter(move |a| a.matches(query))
+    }
+
+    /// Set the label and site, and append a note, of a recorded alias.
+    pub fn annotate(
+        &mut self,
+        address: &str,
+        label: Option<String>,
+        site: Option<String>,
+        note: Option<String>,
+    ) -> Result<&AliasRecord, Box<dyn Error>> {
+        let alias = self
+            .get_mut(address)
+            .ok_or_else(|| format!("Alias `{}` not found in registry", address))?;
+        if label.is_some() {
+            alias.label = label;
+        }
+        if site.is_some() {
+            alias.site = site;
+        }
+        alias.notes.extend(note);
+        Ok(alias)
+    }
+}
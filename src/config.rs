@@ -0,0 +1,647 @@
+//! Module for reading and writing the configuration file
+use fs2::FileExt;
+use schemars::schema::RootSchema;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_yaml::Value as YamlValue;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "ddep")]
+use crate::ddep::DdConfig;
+/// Users of duckduckgo email protection are kept as they are without `ddep`,
+/// so such builds still read and rewrite config files listing them.
+#[cfg(not(feature = "ddep"))]
+type DdConfig = serde_yaml::Value;
+use crate::email::EmailConfig;
+use crate::otp::OtpPattern;
+use crate::providers::{ApiKeyConfig, ProviderKind};
+use crate::template::{Template, TemplateError};
+
+/// Key holding the schema version of a config file
+pub const VERSION_KEY: &str = "version";
+
+/// Upgrade a document by one schema version, in place.
+pub type Migration = fn(&mut YamlValue) -> Result<(), Box<dyn Error>>;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file was written by a newer mail_sitter
+    UnsupportedVersion { found: u32, supported: u32 },
+    /// A field holds a value that cannot be used
+    Invalid { field: String, reason: String },
+}
+
+impl ConfigError {
+    pub fn invalid(field: &str, reason: impl Into<String>) -> Self {
+        Self::Invalid {
+            field: field.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Prefix the field with the section it belongs to.
+    pub fn within(self, section: &str) -> Self {
+        match self {
+            Self::Invalid { field, reason } => Self::Invalid {
+                field: format!("{}.{}", section, field),
+                reason,
+            },
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion { found, supported } => write!(
+                f,
+                "Config version {} is newer than the supported version {}, please upgrade mail_sitter",
+                found, supported
+            ),
+            Self::Invalid { field, reason } => write!(f, "Invalid `{}`: {}", field, reason),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Config trait
+pub trait Config: Sized + Serialize + DeserializeOwned {
+    /// Schema version written by `save` and `replace`.
+    const VERSION: u32 = 1;
+
+    /// Migrations applied by `read`, the n-th one upgrades a document
+    /// from version n to n + 1, so there is one per version.
+    /// Files without a version are version 0.
+    const MIGRATIONS: &'static [Migration] = &[no_change];
+
+    /// Check values serde cannot, called after every load.
+    fn validate(&self) -> Result<(), ConfigError> {
+        Ok(())
+    }
+
+    /// Recursively merge two serde_json::Value objects.
+    fn merge_configs(a: &mut Value, b: &Value) {
+        match (a, b) {
+            (Value::Object(a_obj), Value::Object(b_obj)) => {
+                for (k, v) in b_obj {
+                    Self::merge_configs(a_obj.entry(k).or_insert(Value::Null), v);
+                }
+            }
+            (a, b) => {
+                *a = b.clone();
+            }
+        }
+    }
+
+    /// Bring a raw document up to `VERSION` by running the migration chain.
+    fn migrate(value: &mut YamlValue) -> Result<(), Box<dyn Error>> {
+        let found = version_of(value)?;
+        if found > Self::VERSION {
+            return Err(Box::new(ConfigError::UnsupportedVersion {
+                found,
+                supported: Self::VERSION,
+            }));
+        }
+        for v in found..Self::VERSION {
+            let migration = Self::MIGRATIONS
+                .get(v as usize)
+                .ok_or_else(|| format!("No migration from version {}", v))?;
+            migration(value).map_err(|e| format!("Migrating from version {}: {}", v, e))?;
+        }
+        set_version(value, Self::VERSION);
+        Ok(())
+    }
+
+    /// Migrate, deserialize and validate a raw document.
+    fn from_value(mut value: YamlValue) -> Result<Self, Box<dyn Error>> {
+        Self::migrate(&mut value)?;
+        let config: Self = serde_yaml::from_value(value)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Read config from path
+    fn read(path: &str) -> Result<Self, Box<dyn Error>> {
+        let config_content = fs::read_to_string(path)?;
+        let value: YamlValue = serde_yaml::from_str(&config_content)
+            .map_err(|e| format!("Failed to parse {}: {}", path, e))?;
+        Self::from_value(value).map_err(|e| format!("Failed to load {}: {}", path, e).into())
+    }
+
+    /// Read config from path, or the default one if the file does not exist yet.
+    fn read_or_default(path: &str) -> Result<Self, Box<dyn Error>>
+    where
+        Self: Default,
+    {
+        if Path::new(path).exists() {
+            Self::read(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    /// Read-modify-write the config at path under the config lock.
+    /// Nothing is written when `f` fails.
+    fn update<F, R>(path: &str, f: F) -> Result<R, Box<dyn Error>>
+    where
+        Self: Default,
+        F: FnOnce(&mut Self) -> Result<R, Box<dyn Error>>,
+    {
+        let _lock = lock(path)?;
+        let mut config = Self::read_or_default(path)?;
+        let ret = f(&mut config)?;
+        write_config(&config, path)?;
+        Ok(ret)
+    }
+
+    /// Save config to path, merging into the existing content.
+    /// The existing file is migrated first, and nothing is written unless
+    /// the merged config loads. The read-modify-write is done under the config lock.
+    fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let _lock = lock(path)?;
+        let mut existing = read_value(path)?;
+        Self::migrate(&mut existing)?;
+        let mut merged: Value = serde_json::to_value(&existing)?;
+        Self::merge_configs(&mut merged, &serde_json::to_value(self)?);
+        let mut merged: YamlValue = serde_json::from_value(merged)?;
+        set_version(&mut merged, Self::VERSION);
+        let config = Self::from_value(merged)?;
+        write_config(&config, path)
+    }
+
+    /// Save config to path, replacing the whole file instead of merging
+    /// into the existing content. Keys missing from `self` are dropped.
+    fn replace(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let _lock = lock(path)?;
+        write_config(self, path)
+    }
+}
+
+/// Write a whole config with its version, the caller holds the lock.
+fn write_config<C: Config>(config: &C, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut value = serde_yaml::to_value(config)?;
+    set_version(&mut value, C::VERSION);
+    let content = serde_yaml::to_string(&value)?;
+    write_atomic(Path::new(path), content.as_bytes())
+}
+
+/// Root of the config file.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Schema version of the file
+    #[serde(default)]
+    pub version: u32,
+    /// Email accounts, the first one is used when none is selected
+    #[serde(default)]
+    pub accounts: Vec<EmailConfig>,
+    /// Settings of alias providers
+    #[serde(default)]
+    pub providers: Providers,
+    /// Code extraction rules per sender, for `otp`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub otp: Vec<OtpPattern>,
+    /// Named output templates, used with `--format <name>`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub templates: BTreeMap<String, String>,
+}
+
+/// `providers:` section of the config file.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Providers {
+    /// Users of duckduckgo email protection
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(not(feature = "ddep"), schemars(with = "Vec<serde_json::Value>"))]
+    pub duckduckgo: Vec<DdConfig>,
+    /// SimpleLogin accounts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub simplelogin: Vec<ApiKeyConfig>,
+    /// addy.io accounts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub addy: Vec<ApiKeyConfig>,
+    /// Firefox Relay accounts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub relay: Vec<ApiKeyConfig>,
+}
+
+impl Providers {
+    /// Accounts of a provider logging in with an API key.
+    pub fn api_keys(&self, kind: ProviderKind) -> Option<&Vec<ApiKeyConfig>> {
+        match kind {
+            ProviderKind::Simplelogin => Some(&self.simplelogin),
+            ProviderKind::Addy => Some(&self.addy),
+            ProviderKind::Relay => Some(&self.relay),
+            ProviderKind::Duckduckgo => None,
+        }
+    }
+
+    /// Mutable accounts of a provider logging in with an API key.
+    pub fn api_keys_mut(&mut self, kind: ProviderKind) -> Option<&mut Vec<ApiKeyConfig>> {
+        match kind {
+            ProviderKind::Simplelogin => Some(&mut self.simplelogin),
+            ProviderKind::Addy => Some(&mut self.addy),
+            ProviderKind::Relay => Some(&mut self.relay),
+            ProviderKind::Duckduckgo => None,
+        }
+    }
+}
+
+impl Config for ConfigFile {
+    const VERSION: u32 = 5;
+    const MIGRATIONS: &'static [Migration] = &[
+        no_change,
+        nest_sections,
+        add_templates,
+        add_api_key_providers,
+        add_otp_patterns,
+    ];
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (i, account) in self.accounts.iter().enumerate() {
+            account
+                .validate()
+                .map_err(|e| e.within(&format!("accounts[{}]", i)))?;
+        }
+        for (i, pattern) in self.otp.iter().enumerate() {
+            pattern
+                .validate()
+                .map_err(|e| e.within(&format!("otp[{}]", i)))?;
+        }
+        for (name, template) in &self.templates {
+            template
+                .parse::<Template>()
+                .map_err(|e| ConfigError::invalid(&format!("templates.{}", name), e.to_string()))?;
+        }
+        #[cfg(feature = "ddep")]
+        for (i, user) in self.providers.duckduckgo.iter().enumerate() {
+            user.validate()
+                .map_err(|e| e.within(&format!("providers.duckduckgo[{}]", i)))?;
+        }
+        for kind in [
+            ProviderKind::Simplelogin,
+            ProviderKind::Addy,
+            ProviderKind::Relay,
+        ] {
+            let entries = self.providers.api_keys(kind).into_iter().flatten();
+            for (i, entry) in entries.enumerate() {
+                entry
+                    .validate()
+                    .map_err(|e| e.within(&format!("providers.{}[{}]", kind, i)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ConfigFile {
+    /// Account with the given email, or the first account when `email` is `None`.
+    pub fn account(&self, email: Option<&str>) -> Result<&EmailConfig, Box<dyn Error>> {
+        match email {
+            Some(email) => self
+                .accounts
+                .iter()
+                .find(|a| a.email == email)
+                .ok_or_else(|| format!("Account `{}` not found", email).into()),
+            None => self
+                .accounts
+                .first()
+                .ok_or_else(|| "No account configured, run `init` first".into()),
+        }
+    }
+
+    /// Template named `format` in the config, or `format` itself parsed as a template.
+    pub fn template(&self, format: &str) -> Result<Template, Box<dyn Error>> {
+        let (field, template) = match self.templates.get(format) {
+            Some(template) => (format!("templates.{}", format), template.as_str()),
+            None => ("--format".to_string(), format),
+        };
+        Ok(template
+            .parse()
+            .map_err(|e: TemplateError| ConfigError::invalid(&field, e.to_string()))?)
+    }
+
+    /// Add an account, replacing the one with the same email.
+    pub fn upsert_account(&mut self, account: EmailConfig) {
+        match self.accounts.iter_mut().find(|a| a.email == account.email) {
+            Some(a) => *a = account,
+            None => self.accounts.push(account),
+        }
+    }
+
+    /// Duckduckgo user with the given name, or the first one when `username` is `None`.
+    #[cfg(feature = "ddep")]
+    pub fn duckduckgo(&self, username: Option<&str>) -> Option<&DdConfig> {
+        let users = &self.providers.duckduckgo;
+        match username {
+            Some(username) => users.iter().find(|u| u.username == username),
+            None => users.first(),
+        }
+    }
+
+    /// Add an API key account, replacing the one with the same name.
+    pub fn upsert_api_key(&mut self, kind: ProviderKind, entry: ApiKeyConfig) {
+        if let Some(entries) = self.providers.api_keys_mut(kind) {
+            match entries.iter_mut().find(|e| e.account == entry.account) {
+                Some(e) => *e = entry,
+                None => entries.push(entry),
+            }
+        }
+    }
+
+    /// Add a duckduckgo user, replacing the one with the same username.
+    #[cfg(feature = "ddep")]
+    pub fn upsert_duckduckgo(&mut self, user: DdConfig) {
+        let users = &mut self.providers.duckduckgo;
+        match users.iter_mut().find(|u| u.username == user.username) {
+            Some(u) => *u = user,
+            None => users.push(user),
+        }
+    }
+}
+
+/// JSON Schema of the config file, for editor completion.
+pub fn json_schema() -> RootSchema {
+    schemars::schema_for!(ConfigFile)
+}
+
+/// Version 0 files only lack the version key.
+fn no_change(_: &mut YamlValue) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+/// Version 3 added `templates`. Nothing to change, but as the root rejects
+/// unknown keys, older versions now report the file as too new instead.
+fn add_templates(_: &mut YamlValue) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+/// Version 4 added `providers.simplelogin`, `providers.addy` and `providers.relay`,
+/// which `providers` rejects as unknown in older versions.
+fn add_api_key_providers(_: &mut YamlValue) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+/// Version 5 added the `otp` section of code extraction rules.
+fn add_otp_patterns(_: &mut YamlValue) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+/// Version 1 kept email and duckduckgo keys side by side at the top level,
+/// move them into `accounts` and `providers.duckduckgo`.
+fn nest_sections(value: &mut YamlValue) -> Result<(), Box<dyn Error>> {
+    let root = value
+        .as_mapping_mut()
+        .ok_or("Expected a mapping at the top level")?;
+    let mut take = |keys: &[&str]| {
+        let mut section = serde_yaml::Mapping::new();
+        for key in keys {
+            if let Some(v) = root.remove(*key) {
+                section.insert(YamlValue::from(*key), v);
+            }
+        }
+        section
+    };
+    let account = take(&["email", "pwd", "smtp"]);
+    let duckduckgo = take(&["username", "token", "access_token"]);
+    if !account.is_empty() {
+        root.insert(
+            YamlValue::from("accounts"),
+            YamlValue::Sequence(vec![YamlValue::Mapping(account)]),
+        );
+    }
+    if !duckduckgo.is_empty() {
+        let mut providers = serde_yaml::Mapping::new();
+        providers.insert(
+            YamlValue::from("duckduckgo"),
+            YamlValue::Sequence(vec![YamlValue::Mapping(duckduckgo)]),
+        );
+        root.insert(YamlValue::from("providers"), YamlValue::Mapping(providers));
+    }
+    Ok(())
+}
+
+/// Schema version of a raw document, 0 when it has none.
+pub fn version_of(value: &YamlValue) -> Result<u32, Box<dyn Error>> {
+    match value.get(VERSION_KEY) {
+        None | Some(YamlValue::Null) => Ok(0),
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| ConfigError::invalid(VERSION_KEY, "expected a positive integer").into()),
+    }
+}
+
+fn set_version(value: &mut YamlValue, version: u32) {
+    if let YamlValue::Mapping(m) = value {
+        m.insert(YamlValue::from(VERSION_KEY), YamlValue::from(version));
+    }
+}
+
+/// Guard of the advisory lock on a config file, released on drop.
+#[derive(Debug)]
+pub struct ConfigLock {
+    file: File,
+}
+
+impl Drop for ConfigLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+/// Take an exclusive advisory lock on `<path>.lock`, blocking until
+/// other mailsitter processes release it.
+/// Hold the guard across a read-modify-write of the config.
+pub fn lock(path: &str) -> Result<ConfigLock, Box<dyn Error>> {
+    let lock_path = sibling(Path::new(path), "lock");
+    if let Some(parent) = lock_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = private_options().write(true).open(lock_path)?;
+    file.lock_exclusive()?;
+    Ok(ConfigLock { file })
+}
+
+/// Write `content` to `path` atomically: the data goes to a temp file
+/// next to it, which is synced and renamed over the original.
+/// The previous version is kept as `<path>.bak`.
+/// On Unix both files are only readable by the owner.
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if path.exists() {
+        let previous = fs::read(path)?;
+        write_synced(&sibling(path, "bak"), &previous)?;
+    }
+    let tmp = sibling(path, &format!("tmp.{}", std::process::id()));
+    if let Err(e) = write_synced(&tmp, content) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn write_synced(path: &Path, content: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut file = private_options().write(true).truncate(true).open(path)?;
+    // `mode` only applies on creation, so tighten files that already exist
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(content)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn private_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+/// `<path>.<ext>`, e.g. `config.lock` next to `config`.
+fn sibling(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(ext);
+    path.with_file_name(name)
+}
+
+/// Read the raw config file as an untyped YAML document.
+/// A missing file is treated as an empty mapping.
+pub fn read_value(path: &str) -> Result<YamlValue, Box<dyn Error>> {
+    let config_path = PathBuf::from(path);
+    if !config_path.exists() {
+        return Ok(YamlValue::Mapping(Default::default()));
+    }
+    let content = fs::read_to_string(config_path)?;
+    let value: YamlValue = serde_yaml::from_str(&content)?;
+    match value {
+        // an empty file parses as null
+        YamlValue::Null => Ok(YamlValue::Mapping(Default::default())),
+        v => Ok(v),
+    }
+}
+
+/// Create `<path>.edit` holding `content`, for editing the config by hand.
+/// The file is only readable by the owner, and an existing one is an error
+/// instead of being followed or reused. The caller removes it when done.
+pub fn create_edit_buffer(path: &str, content: &[u8]) -> Result<PathBuf, Box<dyn Error>> {
+    let buffer = sibling(Path::new(path), "edit");
+    let mut options = private_options();
+    options.write(true).create_new(true);
+    let mut file = options.open(&buffer).map_err(|e| {
+        format!(
+            "Cannot create `{}`: {}, remove it if no other edit is running",
+            buffer.display(),
+            e
+        )
+    })?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    Ok(buffer)
+}
+
+/// Write an untyped YAML document to path, replacing the file.
+/// Callers doing a read-modify-write should hold [`lock`].
+pub fn write_value(path: &str, value: &YamlValue) -> Result<(), Box<dyn Error>> {
+    let content = serde_yaml::to_string(value)?;
+    write_atomic(Path::new(path), content.as_bytes())
+}
+
+/// Get the value of a dotted key, e.g. `providers.duckduckgo.username`.
+pub fn get_key<'a>(value: &'a YamlValue, key: &str) -> Option<&'a YamlValue> {
+    key.split('.').try_fold(value, |v, k| match v {
+        YamlValue::Mapping(m) => m.get(k),
+        YamlValue::Sequence(s) => k.parse::<usize>().ok().and_then(|i| s.get(i)),
+        _ => None,
+    })
+}
+
+/// Set the value of a dotted key, creating missing parents: sequences when the next
+/// part is an index, mappings otherwise. An index one past the end appends.
+pub fn set_key(value: &mut YamlValue, key: &str, new: YamlValue) -> Result<(), Box<dyn Error>> {
+    if key.split('.').any(str::is_empty) {
+        return Err(format!("Empty part in key `{}`", key).into());
+    }
+    let mut cur = value;
+    for k in key.split('.') {
+        let index = k.parse::<usize>().ok();
+        if cur.is_null() {
+            *cur = match index {
+                Some(_) => YamlValue::Sequence(vec![]),
+                None => YamlValue::Mapping(Default::default()),
+            };
+        }
+        cur = match (cur, index) {
+            (YamlValue::Mapping(m), _) => m.entry(YamlValue::from(k)).or_insert(YamlValue::Null),
+            (YamlValue::Sequence(s), Some(i)) if i <= s.len() => {
+                if i == s.len() {
+                    s.push(YamlValue::Null);
+                }
+                &mut s[i]
+            }
+            (YamlValue::Sequence(_), _) => {
+                return Err(format!("Index `{}` out of range in key `{}`", k, key).into())
+            }
+            _ => return Err(format!("Parent of `{}` in key `{}` is not a mapping", k, key).into()),
+        };
+    }
+    *cur = new;
+    Ok(())
+}
+
+/// Remove a dotted key. Returns the removed value, if any.
+pub fn unset_key(value: &mut YamlValue, key: &str) -> Option<YamlValue> {
+    match key.rsplit_once('.') {
+        Some((parent, last)) => {
+            let node = key_mut(value, parent)?;
+            remove_child(node, last)
+        }
+        None => remove_child(value, key),
+    }
+}
+
+fn key_mut<'a>(value: &'a mut YamlValue, key: &str) -> Option<&'a mut YamlValue> {
+    key.split('.').try_fold(value, |v, k| match v {
+        YamlValue::Mapping(m) => m.get_mut(k),
+        YamlValue::Sequence(s) => k.parse::<usize>().ok().and_then(move |i| s.get_mut(i)),
+        _ => None,
+    })
+}
+
+fn remove_child(value: &mut YamlValue, key: &str) -> Option<YamlValue> {
+    match value {
+        YamlValue::Mapping(m) => m.remove(key),
+        YamlValue::Sequence(s) => match key.parse::<usize>() {
+            Ok(i) if i < s.len() => Some(s.remove(i)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Parse a value given on the command line as a YAML scalar or flow
+/// collection, so `true`, `42` and `[a, b]` keep their types.
+pub fn parse_value(raw: &str) -> YamlValue {
+    serde_yaml::from_str(raw).unwrap_or_else(|_| YamlValue::String(raw.to_string()))
+}
@@ -1,10 +1,14 @@
+use super::error::{self, DdgError};
+use crate::protocol;
+use crate::providers::{AliasProvider, ProviderKind, ProviderStatus};
+use async_trait::async_trait;
 use reqwest::header::{HeaderMap, HeaderValue, ORIGIN, REFERER, USER_AGENT};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 
 const USER_AGENT_STR: &str =
     "Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/110.0";
-const API_BASE: &str = "https://quack.duckduckgo.com/api";
+pub const API_BASE: &str = "https://quack.duckduckgo.com/api";
 const OTP: &str = "/auth/loginlink";
 const LOGIN: &str = "/auth/login";
 const DASHBOARD: &str = "/email/dashboard";
@@ -15,21 +19,45 @@ struct LoginResponse {
     token: String,
 }
 
-#[derive(Deserialize, Debug)]
-struct DashboardResponse {
-    stats: Stats,
-    user: User,
+/// Response of the dashboard endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Dashboard {
+    pub stats: Stats,
+    pub user: User,
+    /// Pending invites, their shape is undocumented
+    #[serde(default)]
+    pub invites: Vec<serde_json::Value>,
 }
 
-#[derive(Deserialize, Debug)]
-struct Stats {
-    addresses_generated: i32,
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Stats {
+    /// Number of aliases generated by the account
+    pub addresses_generated: u64,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
-    access_token: String,
-    email: String,
+    /// Token used to generate aliases
+    pub access_token: String,
+    /// Real address the aliases forward to
+    pub email: String,
+    /// Username, without `@duck.com`
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub cohort: Option<String>,
+}
+
+/// Summary of an account, see [`Client::account_status`].
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccountStatus {
+    pub username: String,
+    /// Real address the aliases forward to, unknown when the token is invalid
+    pub forwarding: Option<String>,
+    /// Number of generated aliases, unknown when the token is invalid
+    pub aliases: Option<u64>,
+    /// Whether the login token is still accepted
+    pub token_valid: bool,
 }
 
 #[derive(Debug)]
@@ -37,15 +65,22 @@ pub struct Client {
     pub username: String,
     pub token: Option<String>,
     pub access_token: Option<String>,
-    pub generated_addresses: Option<i32>,
+    pub generated_addresses: Option<u64>,
     real_email: Option<String>,
     logged_in: bool,
+    base_url: String,
     session: reqwest::Client,
     headers: HeaderMap,
 }
 
 impl Client {
-    pub fn new(username: String, token: Option<String>, access_token: Option<String>) -> Client {
+    /// `base_url` defaults to [`API_BASE`], override it to talk to a mock server.
+    pub fn new(
+        username: String,
+        token: Option<String>,
+        access_token: Option<String>,
+        base_url: Option<String>,
+    ) -> Client {
         let logged_in = token.is_some() && access_token.is_some();
         let mut headers = HeaderMap::new();
         headers.insert(
@@ -64,78 +99,79 @@ impl Client {
             generated_addresses: None,
             real_email: None,
             logged_in,
+            base_url: base_url.unwrap_or_else(|| API_BASE.to_string()),
             session: reqwest::Client::new(),
             headers,
         }
     }
 
-    pub async fn otp(&self, username: Option<&str>) -> Result<bool, Box<dyn Error>> {
+    /// API base URL, `None` when it is the default one.
+    pub fn base_url(&self) -> Option<&str> {
+        Some(self.base_url.as_str()).filter(|url| *url != API_BASE)
+    }
+
+    /// `Authorization` header with a token.
+    fn bearer(&self, token: Option<&String>) -> Result<HeaderMap, DdgError> {
+        let token = token.ok_or(DdgError::NotLoggedIn)?;
+        let mut headers = self.headers.clone();
+        let value = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| DdgError::Malformed("token is not a valid header value".to_string()))?;
+        headers.insert("Authorization", value);
+        Ok(headers)
+    }
+
+    pub async fn otp(&self, username: Option<&str>) -> Result<bool, DdgError> {
         let username = username.unwrap_or(&self.username);
-        let url = format!("{}{}", API_BASE, OTP);
-        let response = self
-            .session
-            .get(&url)
-            .headers(self.headers.clone())
-            .query(&[("user", username)])
-            .send()
-            .await?;
-        response.error_for_status()?;
+        let url = format!("{}{}", self.base_url, OTP);
+        let response = protocol::send(
+            self.session
+                .get(&url)
+                .headers(self.headers.clone())
+                .query(&[("user", username)]),
+        )
+        .await?;
+        error::check(response).await?;
         Ok(true)
     }
 
-    pub async fn login(
-        &mut self,
-        otp: &str,
-        username: Option<&str>,
-    ) -> Result<String, Box<dyn Error>> {
+    pub async fn login(&mut self, otp: &str, username: Option<&str>) -> Result<String, DdgError> {
         let username = username.unwrap_or(&self.username);
-        let parsed_otp: String;
-        if otp.starts_with("https://") {
-            parsed_otp = otp
-                .split("otp=")
+        let parsed_otp = if otp.starts_with("https://") {
+            otp.split("otp=")
                 .nth(1)
-                .unwrap()
-                .split('&')
-                .next()
-                .unwrap()
+                .and_then(|v| v.split('&').next())
+                .filter(|v| !v.is_empty())
+                .ok_or_else(|| DdgError::InvalidOtp(otp.to_string()))?
                 .to_string()
         } else {
-            parsed_otp = otp.replace(' ', "-");
+            otp.trim().replace(' ', "-")
         };
-        let url = format!("{}{}", API_BASE, LOGIN);
-        let response = self
-            .session
-            .get(&url)
-            .headers(self.headers.clone())
-            .query(&[("user", username), ("otp", &parsed_otp)])
-            .send()
-            .await?;
-        response.error_for_status_ref()?;
-        let login_response: LoginResponse = response.json().await?;
+        let url = format!("{}{}", self.base_url, LOGIN);
+        let response = protocol::send(
+            self.session
+                .get(&url)
+                .headers(self.headers.clone())
+                .query(&[("user", username), ("otp", &parsed_otp)]),
+        )
+        .await?;
+        let login_response: LoginResponse = error::json(response).await?;
         self.token = Some(login_response.token.clone());
         Ok(login_response.token)
     }
 
-    async fn dashboard(&self) -> Result<DashboardResponse, Box<dyn Error>> {
-        let url = format!("{}{}", API_BASE, DASHBOARD);
-        let mut headers = self.headers.clone();
-        if let Some(token) = &self.token {
-            headers.insert(
-                "Authorization",
-                HeaderValue::from_str(&format!("Bearer {}", token))?,
-            );
-        }
-        let response = self.session.get(&url).headers(headers).send().await?;
-        response.error_for_status_ref()?;
-        let dashboard_response: DashboardResponse = response.json().await?;
-        Ok(dashboard_response)
+    /// Account details and stats, needs the login token.
+    pub async fn dashboard(&self) -> Result<Dashboard, DdgError> {
+        let url = format!("{}{}", self.base_url, DASHBOARD);
+        let headers = self.bearer(self.token.as_ref())?;
+        let response = protocol::send(self.session.get(&url).headers(headers)).await?;
+        error::json(response).await
     }
 
     pub async fn full_login(
         &mut self,
         otp: &str,
         username: Option<&str>,
-    ) -> Result<bool, Box<dyn Error>> {
+    ) -> Result<bool, DdgError> {
         let token = self.login(otp, username).await?;
         self.token = Some(token.clone());
         let dashboard_response = self.dashboard().await?;
@@ -146,18 +182,89 @@ impl Client {
         Ok(true)
     }
 
-    pub async fn generate_alias(&self) -> Result<String, Box<dyn Error>> {
-        let url = format!("{}{}", API_BASE, GEN_EMAIL);
-        let mut headers = self.headers.clone();
-        if let Some(access_token) = &self.access_token {
-            headers.insert(
-                "Authorization",
-                HeaderValue::from_str(&format!("Bearer {}", access_token))?,
-            );
+    /// Check the login token against the dashboard, refreshing the access token
+    /// and stats on the way. `Ok(false)` when the token is missing or expired.
+    pub async fn validate_token(&mut self) -> Result<bool, DdgError> {
+        let dashboard_response = match self.dashboard().await {
+            Ok(dashboard) => dashboard,
+            Err(e) if e.needs_login() => {
+                self.logged_in = false;
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+        self.access_token = Some(dashboard_response.user.access_token);
+        self.real_email = Some(dashboard_response.user.email);
+        self.generated_addresses = Some(dashboard_response.stats.addresses_generated);
+        self.logged_in = true;
+        Ok(true)
+    }
+
+    /// Username, forwarding address and alias count, along with whether the token is valid.
+    pub async fn account_status(&self) -> Result<AccountStatus, DdgError> {
+        let mut status = AccountStatus {
+            username: self.username.clone(),
+            forwarding: None,
+            aliases: None,
+            token_valid: false,
+        };
+        match self.dashboard().await {
+            Ok(dashboard) => {
+                status.forwarding = Some(dashboard.user.email);
+                status.aliases = Some(dashboard.stats.addresses_generated);
+                status.token_valid = true;
+            }
+            Err(e) if e.needs_login() => {}
+            Err(e) => return Err(e),
         }
-        let response = self.session.post(&url).headers(headers).send().await?;
-        response.error_for_status_ref()?;
-        let alias_response: serde_json::Value = response.json().await?;
-        Ok(alias_response["address"].as_str().unwrap().to_string())
+        Ok(status)
+    }
+
+    pub async fn generate_alias(&self) -> Result<String, DdgError> {
+        let url = format!("{}{}", self.base_url, GEN_EMAIL);
+        let headers = self.bearer(self.access_token.as_ref())?;
+        let response = protocol::send(self.session.post(&url).headers(headers)).await?;
+        let alias_response: serde_json::Value = error::json(response).await?;
+        alias_response["address"]
+            .as_str()
+            .filter(|addr| !addr.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| DdgError::Malformed(format!("no address in {}", alias_response)))
+    }
+}
+
+#[async_trait(?Send)]
+impl AliasProvider for Client {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::Duckduckgo
+    }
+
+    fn account(&self) -> String {
+        self.username.clone()
+    }
+
+    async fn login(&mut self, credential: &str) -> Result<(), Box<dyn Error>> {
+        self.full_login(credential, None).await?;
+        Ok(())
+    }
+
+    async fn generate(&self) -> Result<String, Box<dyn Error>> {
+        let addr = self.generate_alias().await?;
+        Ok(format!("{}@duck.com", addr))
+    }
+
+    async fn list(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        Err("duckduckgo email protection does not list aliases, see `aliases list`".into())
+    }
+
+    async fn status(&self) -> Result<ProviderStatus, Box<dyn Error>> {
+        let dashboard = self.dashboard().await?;
+        Ok(ProviderStatus {
+            provider: self.kind(),
+            account: self.account(),
+            forwarding: Some(dashboard.user.email),
+            aliases: Some(dashboard.stats.addresses_generated),
+            premium: None,
+        })
     }
 }
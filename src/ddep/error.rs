@@ -0,0 +1,151 @@
+//! Errors of the duckduckgo email protection API
+use chrono::{DateTime, Utc};
+use reqwest::header::{CONTENT_TYPE, RETRY_AFTER};
+use reqwest::{Response, StatusCode};
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum DdgError {
+    /// HTTP 401, the token expired or was revoked, log in again
+    Unauthorized,
+    /// HTTP 403, or an HTML challenge page, duckduckgo thinks we are a bot
+    BotChallenge,
+    /// HTTP 429, wait `retry_after` when the server told us how long
+    RateLimited { retry_after: Option<Duration> },
+    /// Any other unsuccessful HTTP status
+    Status { status: StatusCode, body: String },
+    /// The response is not what the API returns
+    Malformed(String),
+    /// The passphrase or magic link cannot be used
+    InvalidOtp(String),
+    /// The request needs a token we do not have
+    NotLoggedIn,
+    /// Connection, TLS or timeout errors
+    Network(reqwest::Error),
+}
+
+impl DdgError {
+    /// Whether logging in again may fix the error.
+    pub fn needs_login(&self) -> bool {
+        matches!(self, Self::Unauthorized | Self::NotLoggedIn)
+    }
+
+    /// How long to wait before retry `attempt` (from 0), `None` when retrying is pointless.
+    /// Honours `Retry-After`, otherwise doubles from one second.
+    pub fn retry_delay(&self, attempt: u32) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after } => {
+                Some(retry_after.unwrap_or(Duration::from_secs(1 << attempt.min(6))))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DdgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unauthorized => write!(f, "DuckDuckGo token expired, please login again"),
+            Self::BotChallenge => write!(f, "DuckDuckGo thinks you are a bot"),
+            Self::RateLimited {
+                retry_after: Some(d),
+            } => write!(f, "Rate limited by DuckDuckGo, retry in {}s", d.as_secs()),
+            Self::RateLimited { retry_after: None } => write!(f, "Rate limited by DuckDuckGo"),
+            Self::Status { status, body } => {
+                write!(f, "DuckDuckGo answered {}: {}", status, excerpt(body))
+            }
+            Self::Malformed(msg) => write!(f, "Unexpected response from DuckDuckGo: {}", msg),
+            Self::InvalidOtp(otp) => write!(f, "Invalid one-time passphrase or link `{}`", otp),
+            Self::NotLoggedIn => write!(f, "Not logged in to DuckDuckGo"),
+            Self::Network(e) => write!(f, "Cannot reach DuckDuckGo: {}", e),
+        }
+    }
+}
+
+impl Error for DdgError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Network(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for DdgError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Network(e)
+    }
+}
+
+/// Turn unsuccessful responses into the matching error.
+pub(crate) async fn check(response: Response) -> Result<Response, DdgError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| retry_after(v, Utc::now()));
+    let html = is_html(&response);
+    let body = response.text().await.unwrap_or_default();
+    Err(match status {
+        StatusCode::UNAUTHORIZED => DdgError::Unauthorized,
+        StatusCode::FORBIDDEN => DdgError::BotChallenge,
+        StatusCode::TOO_MANY_REQUESTS => DdgError::RateLimited { retry_after },
+        _ if html && is_challenge(&body) => DdgError::BotChallenge,
+        status => DdgError::Status { status, body },
+    })
+}
+
+/// Read a successful response as JSON.
+pub(crate) async fn json<T: DeserializeOwned>(response: Response) -> Result<T, DdgError> {
+    let response = check(response).await?;
+    let html = is_html(&response);
+    let body = response.text().await?;
+    serde_json::from_str(&body).map_err(|e| {
+        if html && is_challenge(&body) {
+            DdgError::BotChallenge
+        } else {
+            DdgError::Malformed(format!("{} in `{}`", e, excerpt(&body)))
+        }
+    })
+}
+
+/// Delay of a `Retry-After` header, in seconds or as an HTTP date.
+/// A date in the past means no delay.
+fn retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((at.with_timezone(&Utc) - now).to_std().unwrap_or_default())
+}
+
+fn is_html(response: &Response) -> bool {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().to_lowercase().starts_with("text/html"))
+}
+
+/// Whether an HTML page is a captcha, only asked of `text/html` responses
+/// as JSON errors may mention a challenge too.
+fn is_challenge(body: &str) -> bool {
+    let body = body.to_lowercase();
+    body.contains("captcha") || body.contains("challenge")
+}
+
+fn excerpt(body: &str) -> String {
+    const MAX: usize = 120;
+    match body.char_indices().nth(MAX) {
+        Some((i, _)) => format!("{}...", &body[..i]),
+        None => body.to_string(),
+    }
+}
@@ -1,51 +1,74 @@
 //! Module for duckduckgo email protection
 
 pub mod api;
-use crate::Config;
-pub use api::Client;
+pub mod error;
+use crate::config::ConfigError;
+use crate::email::{EmailConfig, MailFilter};
+pub use api::{AccountStatus, Client, Dashboard};
+use chrono::Utc;
+pub use error::DdgError;
 use regex::Regex;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
-use std::fs;
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Sender of the login email
+const OTP_SENDER: &str = "support@duck.com";
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct DdConfig {
+    /// Username of duckduckgo email protection, without `@duck.com`
     pub username: String,
+    /// Login token
     pub token: Option<String>,
+    /// Token used to generate aliases
     pub access_token: Option<String>,
-    #[serde(flatten)]
-    other: std::collections::HashMap<String, serde_yaml::Value>,
+    /// API base URL, defaults to `https://quack.duckduckgo.com/api`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
 }
 
-impl Config for DdConfig {
-    fn read(path: &str) -> Result<Self, Box<dyn Error>> {
-        let config_content = fs::read_to_string(path)?;
-        let config: DdConfig = serde_yaml::from_str(&config_content)?;
-        Ok(config)
+impl DdConfig {
+    /// Require a bare username, as `me` in `me@duck.com`.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.username.is_empty() || self.username.contains('@') {
+            return Err(ConfigError::invalid(
+                "username",
+                format!(
+                    "expected a duck address username without `@duck.com`, got `{}`",
+                    self.username
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// create new config instantce
+    pub fn new(username: String, token: Option<String>, access_token: Option<String>) -> Self {
+        Self {
+            username,
+            token,
+            access_token,
+            base_url: None,
+        }
     }
 }
 
 impl From<Client> for DdConfig {
     fn from(client: Client) -> Self {
-        Self::new(client.username, client.token, client.access_token)
+        let base_url = client.base_url().map(str::to_string);
+        Self {
+            base_url,
+            ..Self::new(client.username, client.token, client.access_token)
+        }
     }
 }
 
 impl From<DdConfig> for Client {
     fn from(cfg: DdConfig) -> Self {
-        Self::new(cfg.username, cfg.token, cfg.access_token)
-    }
-}
-
-impl DdConfig {
-    /// create new config instantce
-    pub fn new(username: String, token: Option<String>, access_token: Option<String>) -> Self {
-        Self {
-            username,
-            token,
-            access_token,
-            other: std::collections::HashMap::new(),
-        }
+        Self::new(cfg.username, cfg.token, cfg.access_token, cfg.base_url)
     }
 }
 
@@ -59,3 +82,35 @@ pub fn get_otp_via_mail(mail: &str) -> Option<String> {
         None
     }
 }
+
+/// The login email of duckduckgo, arriving from now on.
+/// Build it before calling [`Client::otp`], so older or parallel login emails are ignored.
+pub async fn otp_mail_filter(account: &EmailConfig) -> Result<MailFilter, Box<dyn Error>> {
+    Ok(MailFilter {
+        from: Some(OTP_SENDER.to_string()),
+        subject: None,
+        to: None,
+        body: Some(Regex::new("one-time passphrase").unwrap()),
+        after: Some(account.watermark().await?),
+        since: Some(Utc::now()),
+    })
+}
+
+/// Finish a login after [`Client::otp`], with the passphrase mailed to `account`.
+/// The login email is marked as seen once used.
+pub async fn complete_login_via_mail(
+    client: &mut Client,
+    account: &EmailConfig,
+    filter: &MailFilter,
+) -> Result<(), Box<dyn Error>> {
+    let email = account
+        .wait_for(filter, Duration::from_secs(30), Duration::from_millis(500))
+        .await?;
+    let otp = get_otp_via_mail(&email.body)
+        .ok_or_else(|| format!("cannot find the one-time passphrase in {:?}", email.body))?;
+    client.full_login(&otp, None).await?;
+    if let Some(uid) = email.uid {
+        account.mark_seen(&[uid]).await?;
+    }
+    Ok(())
+}
@@ -1,18 +1,28 @@
-//! Module for read email via imap
-use crate::Config;
-use colored::*;
-use imap::types::Fetch;
-use imap::Session;
+//! Module for read email via imap, sessions come from the pool of [`crate::mailbox`]
+use crate::config::ConfigError;
+use crate::mailbox::{BlockingResult, ImapSession, Pool, FETCH_BATCH};
+use crate::protocol;
+use crate::template::Template;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::stream::{self, Stream, TryStreamExt};
 use mailparse::MailHeaderMap;
 use native_tls::TlsConnector;
+use regex::Regex;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
+use std::future::Future;
+use std::net::TcpStream;
 use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
 
 #[derive(Debug)]
-struct TimeoutError(String);
+pub struct TimeoutError(String);
 
 impl fmt::Display for TimeoutError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -22,8 +32,304 @@ impl fmt::Display for TimeoutError {
 
 impl Error for TimeoutError {}
 
-/// Email structure, only for text
+/// The wait was cancelled through its [`CancellationToken`].
 #[derive(Debug)]
+pub struct CancelledError;
+
+impl fmt::Display for CancelledError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl Error for CancelledError {}
+
+/// Reported after each check of the inbox by [`EmailConfig::wait_until`].
+#[derive(Debug, Clone, Copy)]
+pub struct WaitProgress {
+    /// Number of checks so far, from 1
+    pub attempt: u32,
+    pub elapsed: Duration,
+    /// Messages given to the predicate during this check
+    pub checked: usize,
+}
+
+/// Messages queued ahead of the consumer of [`EmailConfig::stream`]
+const STREAM_BUFFER: usize = 8;
+
+/// Sent from the blocking fetch to the stream
+enum Fetched {
+    Total(usize),
+    Email(Box<Email>),
+    Failed(Box<dyn Error + Send + Sync>),
+}
+
+/// Blocking side of [`EmailConfig::stream`]: a [`crate::mailbox::Mailbox`], or a fake in tests.
+pub(crate) trait MessageSource: Send + 'static {
+    /// UIDs matching an IMAP search, oldest first unless `newest_first`.
+    fn sorted_uids(&mut self, query: &str, newest_first: bool) -> BlockingResult<Vec<u32>>;
+    /// Messages with the given UIDs, in any order, without the ones that are gone.
+    fn fetch_uids(&mut self, uids: &[u32], peek: bool) -> BlockingResult<Vec<Email>>;
+}
+
+/// Stream of the messages of `source` matching `query`, fetched in batches on a blocking thread.
+/// Nothing is done before the stream is first polled, and the work stops when it is dropped.
+/// `open` gets the source on the blocking thread, `close` is given it back once done.
+pub(crate) fn message_stream<'a, S, O, C>(
+    open: O,
+    close: C,
+    query: &str,
+    options: StreamOptions<'a>,
+) -> impl Stream<Item = Result<Email, Box<dyn Error>>> + 'a
+where
+    S: MessageSource,
+    O: FnOnce() -> S + Send + 'static,
+    C: FnOnce(S) + Send + 'static,
+{
+    let query = query.to_string();
+    let (peek, newest_first, limit) = (options.peek, options.newest_first, options.limit);
+    let start = move || {
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let mut source = open();
+            fetch_messages(&mut source, &query, peek, newest_first, limit, &tx);
+            close(source);
+        });
+        rx
+    };
+    let progress = FetchProgress {
+        fetched: 0,
+        total: None,
+    };
+    stream::unfold(
+        (None, Some(start), options.progress, progress),
+        |(mut rx, mut start, mut callback, mut progress)| async move {
+            if rx.is_none() {
+                rx = Some(start.take()?());
+            }
+            let receiver: &mut mpsc::Receiver<Fetched> = rx.as_mut()?;
+            loop {
+                let email = match receiver.recv().await? {
+                    Fetched::Total(total) => {
+                        progress.total = Some(total);
+                        continue;
+                    }
+                    Fetched::Email(email) => Ok(*email),
+                    Fetched::Failed(e) => Err(e as Box<dyn Error>),
+                };
+                progress.fetched += email.is_ok() as usize;
+                if let Some(callback) = callback.as_mut() {
+                    callback(&progress);
+                }
+                return Some((email, (rx, start, callback, progress)));
+            }
+        },
+    )
+}
+
+/// Worker of [`message_stream`], returns once done, failed, or the stream was dropped.
+fn fetch_messages<S: MessageSource>(
+    source: &mut S,
+    query: &str,
+    peek: bool,
+    newest_first: bool,
+    limit: Option<usize>,
+    tx: &mpsc::Sender<Fetched>,
+) {
+    let uids = match source.sorted_uids(query, newest_first) {
+        Ok(mut uids) => {
+            uids.truncate(limit.unwrap_or(usize::MAX));
+            uids
+        }
+        Err(e) => {
+            let _ = tx.blocking_send(Fetched::Failed(e));
+            return;
+        }
+    };
+    // the receiver is gone when the stream was dropped
+    if tx.blocking_send(Fetched::Total(uids.len())).is_err() {
+        return;
+    }
+    for batch in uids.chunks(FETCH_BATCH) {
+        let mut by_uid: HashMap<u32, Email> = match source.fetch_uids(batch, peek) {
+            Ok(emails) => emails
+                .into_iter()
+                .filter_map(|e| e.uid.map(|uid| (uid, e)))
+                .collect(),
+            Err(e) => {
+                let _ = tx.blocking_send(Fetched::Failed(e));
+                return;
+            }
+        };
+        // servers answer in their own order, keep the sorted one
+        for uid in batch {
+            let Some(email) = by_uid.remove(uid) else {
+                continue;
+            };
+            if tx.blocking_send(Fetched::Email(Box::new(email))).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Reported for each message of [`EmailConfig::stream`].
+#[derive(Debug, Clone, Copy)]
+pub struct FetchProgress {
+    /// Messages yielded so far
+    pub fetched: usize,
+    /// Messages matching the search, once the search is done
+    pub total: Option<usize>,
+}
+
+/// Options of [`EmailConfig::stream`].
+#[derive(Default)]
+pub struct StreamOptions<'a> {
+    /// Leave the messages unseen
+    pub peek: bool,
+    /// Yield the most recently received messages first, instead of the oldest
+    pub newest_first: bool,
+    /// Stop after this many messages
+    pub limit: Option<usize>,
+    pub progress: Option<ProgressFn<'a, FetchProgress>>,
+}
+
+/// IMAP search of `ms fetch`.
+#[derive(Debug, Clone, Default)]
+pub struct FetchQuery {
+    /// Only unread messages
+    pub unseen: bool,
+    /// Received on or after this day
+    pub since: Option<NaiveDate>,
+    /// Received before this day
+    pub before: Option<NaiveDate>,
+}
+
+impl FetchQuery {
+    pub fn to_imap(&self) -> String {
+        let mut query = vec![];
+        if self.unseen {
+            query.push("UNSEEN".to_string());
+        }
+        if let Some(since) = self.since {
+            query.push(format!("SINCE {}", since.format("%d-%b-%Y")));
+        }
+        if let Some(before) = self.before {
+            query.push(format!("BEFORE {}", before.format("%d-%b-%Y")));
+        }
+        if query.is_empty() {
+            query.push("ALL".to_string());
+        }
+        query.join(" ")
+    }
+}
+
+/// Poll loop of [`EmailConfig::wait_until`], `search` giving the UIDs matching
+/// its query on each check, and `fetch` the messages of the UIDs not checked yet.
+pub(crate) async fn poll_until<S, SF, G, GF, P, F>(
+    mut search: S,
+    mut fetch: G,
+    mut predicate: P,
+    mut options: WaitOptions<'_>,
+) -> Result<Email, Box<dyn Error>>
+where
+    S: FnMut() -> SF,
+    SF: Future<Output = Result<Vec<u32>, Box<dyn Error>>>,
+    G: FnMut(Vec<u32>) -> GF,
+    GF: Future<Output = Result<Vec<Email>, Box<dyn Error>>>,
+    P: FnMut(&Email) -> F,
+    F: Future<Output = bool>,
+{
+    let start_time = Instant::now();
+    let mut checked_uids = HashSet::new();
+    let cancelled = |options: &WaitOptions| {
+        options
+            .cancel
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    };
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        if cancelled(&options) {
+            return Err(Box::new(CancelledError));
+        }
+        let mut uids: Vec<u32> = search().await?;
+        uids.retain(|uid| !checked_uids.contains(uid));
+        uids.sort_unstable();
+        if options.newest_first {
+            uids.reverse();
+        }
+        let mut checked = 0;
+        for batch in uids.chunks(FETCH_BATCH) {
+            let mut by_uid: HashMap<u32, Email> = fetch(batch.to_vec())
+                .await?
+                .into_iter()
+                .filter_map(|e| e.uid.map(|uid| (uid, e)))
+                .collect();
+            for uid in batch {
+                checked_uids.insert(*uid);
+                // deleted since the search
+                let Some(email) = by_uid.remove(uid) else {
+                    continue;
+                };
+                checked += 1;
+                if predicate(&email).await {
+                    return Ok(email);
+                }
+            }
+        }
+        let elapsed = start_time.elapsed();
+        if let Some(progress) = options.progress.as_mut() {
+            progress(&WaitProgress {
+                attempt,
+                elapsed,
+                checked,
+            });
+        }
+        if elapsed >= options.timeout {
+            return Err(Box::new(TimeoutError(
+                "Timeout, no matching email".to_string(),
+            )));
+        }
+        match &options.cancel {
+            Some(token) => tokio::select! {
+                _ = sleep(options.period) => {}
+                _ = token.cancelled() => return Err(Box::new(CancelledError)),
+            },
+            None => sleep(options.period).await,
+        }
+    }
+}
+
+/// Callback receiving [`WaitProgress`] or [`FetchProgress`]
+pub type ProgressFn<'a, P = WaitProgress> = Box<dyn FnMut(&P) + 'a>;
+
+/// How long and how often [`EmailConfig::wait_until`] checks the inbox.
+pub struct WaitOptions<'a> {
+    pub timeout: Duration,
+    pub period: Duration,
+    /// Check the most recently received messages first, instead of the oldest
+    pub newest_first: bool,
+    /// Stops the wait with [`CancelledError`]
+    pub cancel: Option<CancellationToken>,
+    pub progress: Option<ProgressFn<'a>>,
+}
+
+impl Default for WaitOptions<'_> {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+            period: Duration::from_secs(2),
+            newest_first: false,
+            cancel: None,
+            progress: None,
+        }
+    }
+}
+
+/// Email structure, only for text
+#[derive(Serialize, Debug, Default)]
 pub struct Email {
     /// from
     pub from: String,
@@ -31,19 +337,206 @@ pub struct Email {
     pub subject: String,
     /// email body
     pub body: String,
+    /// addresses of To, Cc, Delivered-To and X-Original-To
+    pub to: Vec<String>,
+    /// Date header
+    pub date: Option<DateTime<Utc>>,
+    /// all headers in order, with values decoded
+    pub headers: Vec<(String, String)>,
+    /// IMAP UID, when fetched by UID
+    pub uid: Option<u32>,
+    /// INTERNALDATE, when the server received the message
+    pub received: Option<DateTime<Utc>>,
+}
+
+impl Email {
+    /// Address part of the From header.
+    pub fn from_addr(&self) -> Option<String> {
+        parse_addrs(&self.from).into_iter().next()
+    }
+
+    /// Display name of the From header, e.g. `Alice` in `Alice <alice@example.com>`.
+    pub fn from_name(&self) -> Option<String> {
+        mailparse::addrparse(&self.from)
+            .ok()?
+            .extract_single_info()?
+            .display_name
+    }
+
+    /// Values of all headers with the given name, case insensitive.
+    pub fn header<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Envelope of a message, listed without downloading it.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Summary {
+    pub uid: u32,
+    /// INTERNALDATE, when the server received the message
+    pub received: Option<DateTime<Utc>>,
+    /// Sender, `name <address>` when it has a name
+    pub from: String,
+    pub subject: String,
+    /// RFC822.SIZE in bytes
+    pub size: Option<u32>,
+    /// Flags without the backslash, e.g. `seen`
+    pub flags: Vec<String>,
+}
+
+/// Options of [`EmailConfig::list`].
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    /// List the most recently received messages first, instead of the oldest
+    pub newest_first: bool,
+    /// Stop after this many messages
+    pub limit: Option<usize>,
+}
+
+/// Position in a mailbox, messages with a UID above `uid` are new.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermark {
+    /// UIDVALIDITY of the mailbox, UIDs are meaningless once it changes
+    pub uid_validity: u32,
+    pub uid: u32,
+}
+
+impl Watermark {
+    /// Whether message `uid` of a mailbox at `uid_validity` is at or below the watermark.
+    pub fn covers(&self, uid_validity: u32, uid: u32) -> bool {
+        self.uid_validity == uid_validity && uid <= self.uid
+    }
+}
+
+/// Addresses in an address list header, lowercased.
+pub fn parse_addrs(value: &str) -> Vec<String> {
+    let Ok(list) = mailparse::addrparse(value) else {
+        return vec![];
+    };
+    list.iter()
+        .flat_map(|addr| match addr {
+            mailparse::MailAddr::Single(info) => vec![info.addr.to_lowercase()],
+            mailparse::MailAddr::Group(group) => {
+                group.addrs.iter().map(|i| i.addr.to_lowercase()).collect()
+            }
+        })
+        .collect()
+}
+
+/// What `EmailConfig::wait_for` waits for, unset fields match anything.
+#[derive(Debug, Default, Clone)]
+pub struct MailFilter {
+    /// Substring of the From header, searched by the server
+    pub from: Option<String>,
+    /// Substring of the subject, searched by the server
+    pub subject: Option<String>,
+    /// Address the message was sent to, searched by the server
+    pub to: Option<String>,
+    /// Regex the body must match, checked locally
+    pub body: Option<Regex>,
+    /// Only messages arriving after this position
+    pub after: Option<Watermark>,
+    /// Only messages received at or after this time
+    pub since: Option<DateTime<Utc>>,
+}
+
+/// Allowed difference between our clock and the one of the mail server
+const CLOCK_SKEW: chrono::Duration = chrono::Duration::minutes(2);
+
+impl MailFilter {
+    /// IMAP search for the criteria the server can check.
+    pub fn query(&self) -> Result<String, Box<dyn Error>> {
+        let mut query = vec![];
+        if let Some(from) = &self.from {
+            query.push(format!("FROM {}", quote(from)?));
+        }
+        if let Some(subject) = &self.subject {
+            query.push(format!("SUBJECT {}", quote(subject)?));
+        }
+        if let Some(to) = &self.to {
+            query.push(format!("TO {}", quote(to)?));
+        }
+        if let Some(after) = self.after {
+            query.push(format!("UID {}:*", after.uid + 1));
+        }
+        if let Some(since) = self.since {
+            // SINCE only compares dates, `matches` checks the time
+            let day = since - CLOCK_SKEW;
+            query.push(format!("SINCE {}", day.format("%d-%b-%Y")));
+        }
+        if query.is_empty() {
+            query.push("ALL".to_string());
+        }
+        Ok(query.join(" "))
+    }
+
+    /// Check a fetched message against every criterion, the server search is loose.
+    pub fn matches(&self, email: &Email) -> bool {
+        let contains = |haystack: &str, needle: &Option<String>| {
+            needle
+                .as_ref()
+                .is_none_or(|n| haystack.to_lowercase().contains(&n.to_lowercase()))
+        };
+        contains(&email.from, &self.from)
+            && contains(&email.subject, &self.subject)
+            && self.body.as_ref().is_none_or(|re| re.is_match(&email.body))
+            && self
+                .to
+                .as_ref()
+                .is_none_or(|to| email.to.iter().any(|a| a.eq_ignore_ascii_case(to)))
+            && self
+                .after
+                .is_none_or(|w| email.uid.is_some_and(|uid| uid > w.uid))
+            && self.since.is_none_or(|since| {
+                // without a date the UID watermark is all we have
+                email
+                    .received
+                    .or(email.date)
+                    .is_none_or(|at| at >= since - CLOCK_SKEW)
+            })
+    }
+}
+
+/// Quote a value for an IMAP search.
+fn quote(value: &str) -> Result<String, Box<dyn Error>> {
+    if value.contains(['"', '\\', '\r', '\n']) {
+        return Err(format!(
+            "Cannot search for `{}`, it contains quotes or newlines",
+            value
+        )
+        .into());
+    }
+    Ok(format!("\"{}\"", value))
 }
 
 impl std::fmt::Display for Email {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "({}\n, {}\n, {}\n)", self.from, self.subject, self.body)
+        write!(f, "{}", Template::default().render(self))
     }
 }
 
 impl<'a> From<mailparse::ParsedMail<'a>> for Email {
     fn from(email: mailparse::ParsedMail) -> Self {
         let headers = email.get_headers();
-        let from = headers.get_first_header("From").unwrap().get_value();
-        let subject = headers.get_first_header("Subject").unwrap().get_value();
+        let from = headers.get_first_value("From").unwrap_or_default();
+        let subject = headers.get_first_value("Subject").unwrap_or_default();
+        let to = ["To", "Cc", "Delivered-To", "X-Original-To"]
+            .iter()
+            .flat_map(|key| headers.get_all_values(key))
+            .flat_map(|v| parse_addrs(&v))
+            .collect();
+        let date = headers
+            .get_first_value("Date")
+            .and_then(|d| mailparse::dateparse(&d).ok())
+            .and_then(|ts| DateTime::from_timestamp(ts, 0));
+        let all_headers = email
+            .headers
+            .iter()
+            .map(|h| (h.get_key(), h.get_value()))
+            .collect();
         let body = if email.ctype.mimetype.starts_with("text/plain") {
             email.get_body().unwrap()
         } else {
@@ -64,57 +557,246 @@ impl<'a> From<mailparse::ParsedMail<'a>> for Email {
             from,
             subject,
             body,
+            to,
+            date,
+            headers: all_headers,
+            uid: None,
+            received: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Leaf MIME parts of a message, depth first, numbered from 1 by `show --part`.
+pub fn leaf_parts<'a, 'b>(
+    mail: &'b mailparse::ParsedMail<'a>,
+) -> Vec<&'b mailparse::ParsedMail<'a>> {
+    if mail.subparts.is_empty() {
+        return vec![mail];
+    }
+    mail.subparts.iter().flat_map(leaf_parts).collect()
+}
+
+/// Readable text of an HTML body, for messages without a text part.
+pub fn html_to_text(html: &str) -> String {
+    let hidden =
+        Regex::new(r"(?is)<head\b.*?</head>|<script\b.*?</script>|<style\b.*?</style>").unwrap();
+    let breaks = Regex::new(r"(?i)<br\s*/?>|</(?:p|div|tr|li|h[1-6]|table)\s*>").unwrap();
+    let tags = Regex::new(r"(?s)<[^>]*>").unwrap();
+    let blank = Regex::new(r"\n{3,}").unwrap();
+    let text = hidden.replace_all(html, "");
+    let text = breaks.replace_all(&text, "\n");
+    let text = tags.replace_all(&text, "");
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    let text = text
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>()
+        .join("\n");
+    blank.replace_all(&text, "\n\n").trim().to_string()
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct EmailConfig {
+    /// Email address, also used as the IMAP login
     pub email: String,
+    /// Password, or app password for gmail
     pub pwd: String,
-    pub smtp: String, // format: <addr>:<port>
-
-    #[serde(flatten)]
-    other: std::collections::HashMap<String, serde_yaml::Value>,
+    /// IMAP server address and port in the format <addr>:<port>
+    #[schemars(regex(pattern = r"^[^:\s]+:\d+$"))]
+    pub smtp: String,
 }
 
-impl Config for EmailConfig {}
-
 impl EmailConfig {
-    pub fn new(email: String, pwd: String, smtp: String) -> Self {
-        Self {
-            email,
-            pwd,
-            smtp,
-            other: std::collections::HashMap::new(),
+    /// Require an email address, a password and an `<addr>:<port>` server.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !self.email.contains('@') {
+            return Err(ConfigError::invalid(
+                "email",
+                format!("expected an email address, got `{}`", self.email),
+            ));
         }
+        if self.pwd.is_empty() {
+            return Err(ConfigError::invalid("pwd", "password is empty"));
+        }
+        split_smtp(&self.smtp).map_err(|e| ConfigError::invalid("smtp", e.to_string()))?;
+        Ok(())
+    }
+
+    pub fn new(email: String, pwd: String, smtp: String) -> Self {
+        Self { email, pwd, smtp }
     }
 
-    pub fn fetch_email(&self, kind: &str) -> Result<Vec<Email>, Box<dyn Error>> {
+    /// Connect and log in, see [`crate::mailbox::Mailbox`] for a session reused across operations.
+    pub(crate) fn connect(&self) -> BlockingResult<ImapSession> {
         let (smtp_server, smtp_port) = split_smtp(&self.smtp)?;
 
         let tls = TlsConnector::builder().build()?;
-        let client = imap::connect((smtp_server.as_str(), smtp_port), &smtp_server, &tls)?;
-
-        let mut imap_session: Session<_> = client.login(&self.email, &self.pwd).map_err(|e| e.0)?;
-
-        imap_session.select("inbox")?;
-        let msgs = imap_session.search(kind)?;
-        let emails = msgs.iter().fold(vec![], |mut ret, mid| {
-            let message = imap_session.fetch(mid.to_string(), "RFC822").unwrap();
-            let fetch: &Fetch = message.iter().next().ok_or("No message").unwrap();
-            let msg = fetch.body().ok_or("No message content").unwrap();
-            if let Ok(msg) = std::str::from_utf8(msg) {
-                let email: Email = mailparse::parse_mail(msg.as_bytes()).unwrap().into();
-                println!("{}", email.to_string().as_str().blue());
-                ret.push(email);
-            }
-            ret
-        });
-        imap_session.logout()?;
-        Ok(emails)
+        debug!(
+            "Connecting to {}:{} as {}",
+            smtp_server, smtp_port, self.email
+        );
+        let tcp = TcpStream::connect((smtp_server.as_str(), smtp_port))?;
+        let stream = protocol::Traced::new(tls.connect(&smtp_server, tcp)?);
+        let mut client = imap::Client::new(stream);
+        client.read_greeting()?;
+
+        let imap_session = client.login(&self.email, &self.pwd).map_err(|e| e.0)?;
+        Ok(imap_session)
+    }
+
+    /// Messages matching an IMAP search, marking them seen.
+    /// Collects [`EmailConfig::stream`], use it for large mailboxes.
+    pub async fn fetch_email(&self, kind: &str) -> Result<Vec<Email>, Box<dyn Error>> {
+        self.stream(kind, StreamOptions::default())
+            .try_collect()
+            .await
+    }
+
+    /// Envelopes of the messages matching an IMAP search, in the order they were received.
+    /// Only ENVELOPE, FLAGS, INTERNALDATE and RFC822.SIZE are fetched, and nothing is marked seen.
+    pub async fn list(
+        &self,
+        query: &str,
+        options: ListOptions,
+    ) -> Result<Vec<Summary>, Box<dyn Error>> {
+        let query = query.to_string();
+        Pool::global()
+            .run(self, move |m| {
+                let mut uids = m.sorted_uids(&query, options.newest_first)?;
+                uids.truncate(options.limit.unwrap_or(usize::MAX));
+                m.summaries(&uids)
+            })
+            .await
+    }
+
+    /// Messages matching an IMAP search in the order they were received,
+    /// fetched in batches on a blocking thread while the stream is consumed.
+    /// The search starts on the first poll, and stops after the first error.
+    pub fn stream<'a>(
+        &self,
+        query: &str,
+        options: StreamOptions<'a>,
+    ) -> impl Stream<Item = Result<Email, Box<dyn Error>>> + 'a {
+        let account = self.clone();
+        message_stream(
+            move || Pool::global().take(&account),
+            |mailbox| Pool::global().put_back(mailbox),
+            query,
+            options,
+        )
+    }
+
+    /// Read messages of the inbox matching an IMAP search, marking them seen.
+    pub async fn read(&self, kind: &str) -> Result<Vec<Email>, Box<dyn Error>> {
+        let kind = kind.to_string();
+        Pool::global().run(self, move |m| m.read(&kind)).await
+    }
+
+    /// Read messages of the inbox above `since` without marking them seen,
+    /// and return the watermark to pass next time.
+    /// Starts from the first message when `since` is `None` or stale.
+    pub async fn fetch_new(
+        &self,
+        since: Option<Watermark>,
+    ) -> Result<(Watermark, Vec<Email>), Box<dyn Error>> {
+        Pool::global().run(self, move |m| m.fetch_new(since)).await
+    }
+
+    /// Read messages of the inbox matching an IMAP search, without marking them seen.
+    pub async fn search(&self, query: &str) -> Result<Vec<Email>, Box<dyn Error>> {
+        let query = query.to_string();
+        Pool::global().run(self, move |m| m.search(&query)).await
+    }
+
+    /// UIDs of the messages matching an IMAP search, in ascending order.
+    pub async fn uid_search(&self, query: &str) -> Result<Vec<u32>, Box<dyn Error>> {
+        let query = query.to_string();
+        Pool::global()
+            .run(self, move |m| m.uid_search(&query))
+            .await
+    }
+
+    /// Messages with `uids`, without marking them seen.
+    pub async fn fetch_uids(&self, uids: &[u32]) -> Result<Vec<Email>, Box<dyn Error>> {
+        let uids = uids.to_vec();
+        Pool::global()
+            .run(self, move |m| m.fetch_uids(&uids, true))
+            .await
+    }
+
+    /// Original source of the message with `uid`, without marking it seen.
+    pub async fn raw(&self, uid: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+        Pool::global()
+            .run(self, move |m| m.fetch_raw(uid))
+            .await?
+            .ok_or_else(|| format!("No message with UID {}", uid).into())
+    }
+
+    /// Current end of the inbox, messages arriving later are above it.
+    pub async fn watermark(&self) -> Result<Watermark, Box<dyn Error>> {
+        Pool::global().run(self, |m| m.watermark()).await
+    }
+
+    /// Poll the inbox until a message matches `filter`, without marking it seen.
+    /// Fails on the first IMAP error, or with [`TimeoutError`].
+    pub async fn wait_for(
+        &self,
+        filter: &MailFilter,
+        timeout: Duration,
+        period: Duration,
+    ) -> Result<Email, Box<dyn Error>> {
+        let options = WaitOptions {
+            timeout,
+            period,
+            ..Default::default()
+        };
+        let query = filter.query()?;
+        self.wait_until(&query, |e| std::future::ready(filter.matches(e)), options)
+            .await
+    }
+
+    /// Poll the messages matching the IMAP search `query`, oldest first unless
+    /// [`WaitOptions::newest_first`], until `predicate` accepts one. Messages are not
+    /// marked seen, and each one is downloaded and given to the predicate once. The future it returns cannot borrow the email,
+    /// clone what it needs.
+    /// Fails on the first IMAP error, with [`TimeoutError`] or [`CancelledError`].
+    pub async fn wait_until<P, F>(
+        &self,
+        query: &str,
+        predicate: P,
+        options: WaitOptions<'_>,
+    ) -> Result<Email, Box<dyn Error>>
+    where
+        P: FnMut(&Email) -> F,
+        F: Future<Output = bool>,
+    {
+        poll_until(
+            || self.uid_search(query),
+            |uids| async move { self.fetch_uids(&uids).await },
+            predicate,
+            options,
+        )
+        .await
+    }
+
+    /// Flag messages of the inbox as seen.
+    pub async fn mark_seen(&self, uids: &[u32]) -> Result<(), Box<dyn Error>> {
+        if uids.is_empty() {
+            return Ok(());
+        }
+        let uids = uids.to_vec();
+        Pool::global().run(self, move |m| m.mark_seen(&uids)).await
     }
 
+    /// Poll until the IMAP search matches, and read the matches marking them seen.
+    /// Errors are retried until the timeout, which reports the last one.
     pub async fn fetch_until(
         &self,
         filter: &str,
@@ -123,36 +805,41 @@ impl EmailConfig {
     ) -> Result<Vec<Email>, Box<dyn Error>> {
         let timeout_duration = Duration::from_secs(timeout);
         let start_time = Instant::now();
+        let mut last_error = None;
 
         loop {
-            match self.fetch_email(filter) {
+            match self.read(filter).await {
                 Ok(emails) => {
                     if !emails.is_empty() {
                         return Ok(emails);
                     }
                 }
                 Err(err) => {
-                    println!("Error: {}", err);
+                    warn!("Reading {} failed, retrying: {}", self.email, err);
+                    last_error = Some(err.to_string());
                 }
             }
 
             if start_time.elapsed() >= timeout_duration {
-                println!("Timeout reached");
-                return Err(Box::new(TimeoutError(
-                    "Timeout, cannot find new email".to_string(),
-                )));
+                let reason = match last_error {
+                    Some(err) => format!("Timeout, cannot find new email, last error: {}", err),
+                    None => "Timeout, cannot find new email".to_string(),
+                };
+                return Err(Box::new(TimeoutError(reason)));
             }
             sleep(Duration::from_secs_f64(period)).await;
         }
     }
 }
 
-fn split_smtp(smtp: &str) -> Result<(String, u16), Box<dyn Error>> {
+fn split_smtp(smtp: &str) -> BlockingResult<(String, u16)> {
     let parts: Vec<&str> = smtp.split(':').collect();
-    if parts.len() != 2 {
-        return Err("Invalid SMTP format, expected <addr>:<port>".into());
+    if parts.len() != 2 || parts[0].is_empty() {
+        return Err(format!("Invalid SMTP format `{}`, expected <addr>:<port>", smtp).into());
     }
     let addr = parts[0].to_string();
-    let port: u16 = parts[1].parse()?;
+    let port: u16 = parts[1]
+        .parse()
+        .map_err(|_| format!("Invalid port `{}` in `{}`", parts[1], smtp))?;
     Ok((addr, port))
 }
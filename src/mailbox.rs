@@ -0,0 +1,517 @@
+//! Long lived IMAP sessions, reused across operations and pooled per account
+use crate::email::{Email, EmailConfig, MessageSource, Summary, Watermark};
+use crate::protocol::Traced;
+use chrono::Utc;
+use imap::types::{Fetch, Flag};
+use imap::Session;
+use native_tls::TlsStream;
+use std::collections::HashMap;
+use std::error::Error;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Errors of the IMAP work done on blocking threads
+pub type BlockingResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+pub type ImapSession = Session<Traced<TlsStream<TcpStream>>>;
+
+/// Idle time after which a session is checked with NOOP before use,
+/// servers drop idle connections after 30 minutes at the earliest
+const IDLE_CHECK: Duration = Duration::from_secs(5 * 60);
+/// UIDs per FETCH when listing envelopes
+const LIST_BATCH: usize = 500;
+/// UIDs per FETCH when downloading whole messages, which are much larger than envelopes
+pub(crate) const FETCH_BATCH: usize = 50;
+/// Idle sessions kept per account
+const MAX_IDLE: usize = 4;
+
+/// Authenticated connection to the inbox of an account.
+/// Connects on first use and after the connection dropped, and retries
+/// the read-only operations once when it drops while they run.
+/// Every method blocks, see [`Pool`] to use it from async code.
+pub struct Mailbox {
+    account: EmailConfig,
+    session: Option<ImapSession>,
+    last_used: Instant,
+}
+
+impl Mailbox {
+    pub fn new(account: EmailConfig) -> Self {
+        Self {
+            account,
+            session: None,
+            last_used: Instant::now(),
+        }
+    }
+
+    pub fn account(&self) -> &EmailConfig {
+        &self.account
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Run `op` on the session once, connecting first when needed.
+    /// When the connection is lost meanwhile, the error is returned and
+    /// the next operation reconnects, as `op` may have taken effect.
+    pub fn run<T>(
+        &mut self,
+        op: impl FnOnce(&mut ImapSession) -> BlockingResult<T>,
+    ) -> BlockingResult<T> {
+        if self.last_used.elapsed() >= IDLE_CHECK && self.noop().is_err() {
+            self.session = None;
+        }
+        let result = op(self.session()?);
+        if result.as_ref().is_err_and(|e| is_disconnect(e.as_ref())) {
+            debug!("Connection to {} lost", self.account.email);
+            self.session = None;
+        }
+        self.last_used = Instant::now();
+        result
+    }
+
+    /// Like [`Mailbox::run`], but reconnect and run `op` again, once, when the
+    /// connection was lost. Only for operations that are safe to repeat.
+    pub fn run_idempotent<T>(
+        &mut self,
+        mut op: impl FnMut(&mut ImapSession) -> BlockingResult<T>,
+    ) -> BlockingResult<T> {
+        retry_once(|| self.run(&mut op))
+    }
+
+    /// Keep the connection alive, fails when it dropped.
+    pub fn noop(&mut self) -> BlockingResult<()> {
+        if let Some(session) = self.session.as_mut() {
+            if let Err(e) = session.noop() {
+                debug!("Idle connection to {} dropped: {}", self.account.email, e);
+                self.session = None;
+                return Err(e.into());
+            }
+            self.last_used = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Log out, the next operation connects again.
+    pub fn close(&mut self) -> BlockingResult<()> {
+        if let Some(mut session) = self.session.take() {
+            session.logout()?;
+        }
+        Ok(())
+    }
+
+    fn session(&mut self) -> BlockingResult<&mut ImapSession> {
+        if self.session.is_none() {
+            let mut session = self.account.connect()?;
+            session.select("inbox")?;
+            self.session = Some(session);
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+
+    /// Read messages matching an IMAP search, marking them seen.
+    pub fn read(&mut self, kind: &str) -> BlockingResult<Vec<Email>> {
+        self.run(|session| {
+            let msgs = session.search(kind)?;
+            let mut emails = vec![];
+            for mid in msgs.iter() {
+                let message = session.fetch(mid.to_string(), "RFC822")?;
+                let fetch: &Fetch = message.iter().next().ok_or("No message")?;
+                let msg = fetch.body().ok_or("No message content")?;
+                if let Ok(msg) = std::str::from_utf8(msg) {
+                    emails.push(mailparse::parse_mail(msg.as_bytes())?.into());
+                }
+            }
+            Ok(emails)
+        })
+    }
+
+    /// Read messages above `since` without marking them seen,
+    /// and return the watermark to pass next time.
+    /// Starts from the first message when `since` is `None` or stale.
+    pub fn fetch_new(
+        &mut self,
+        since: Option<Watermark>,
+    ) -> BlockingResult<(Watermark, Vec<Email>)> {
+        self.run_idempotent(|session| {
+            // select again for a fresh UIDVALIDITY
+            let mailbox = session.select("inbox")?;
+            let uid_validity = mailbox.uid_validity.unwrap_or(0);
+            let first = match since {
+                Some(w) if w.uid_validity == uid_validity => w.uid + 1,
+                _ => 1,
+            };
+            let mut uids: Vec<u32> = session
+                .uid_search(format!("UID {}:*", first))?
+                .into_iter()
+                // `n:*` also matches the last message when its UID is below n
+                .filter(|&uid| uid >= first)
+                .collect();
+            uids.sort_unstable();
+            let watermark = Watermark {
+                uid_validity,
+                uid: uids.last().copied().unwrap_or(first - 1),
+            };
+            Ok((watermark, peek_batches(session, &uids)?))
+        })
+    }
+
+    /// Read messages matching an IMAP search, without marking them seen.
+    pub fn search(&mut self, query: &str) -> BlockingResult<Vec<Email>> {
+        self.run_idempotent(|session| {
+            let mut uids: Vec<u32> = session.uid_search(query)?.into_iter().collect();
+            uids.sort_unstable();
+            peek_batches(session, &uids)
+        })
+    }
+
+    /// Current end of the inbox, messages arriving later are above it.
+    pub fn watermark(&mut self) -> BlockingResult<Watermark> {
+        self.run_idempotent(|session| {
+            let mailbox = session.select("inbox")?;
+            let uid = match mailbox.uid_next {
+                Some(next) => next.saturating_sub(1),
+                None => session.uid_search("ALL")?.into_iter().max().unwrap_or(0),
+            };
+            Ok(Watermark {
+                uid_validity: mailbox.uid_validity.unwrap_or(0),
+                uid,
+            })
+        })
+    }
+
+    /// UIDs of the messages matching an IMAP search, in ascending order.
+    pub fn uid_search(&mut self, query: &str) -> BlockingResult<Vec<u32>> {
+        self.run_idempotent(|session| {
+            let mut uids: Vec<u32> = session.uid_search(query)?.into_iter().collect();
+            uids.sort_unstable();
+            Ok(uids)
+        })
+    }
+
+    /// UIDs of the messages matching an IMAP search, by arrival time.
+    /// Uses the SORT extension when the server has it, otherwise sorts by INTERNALDATE.
+    pub fn sorted_uids(&mut self, query: &str, newest_first: bool) -> BlockingResult<Vec<u32>> {
+        self.run_idempotent(|session| {
+            let mut uids = if session.capabilities()?.has_str("SORT") {
+                let command = format!("UID SORT (ARRIVAL) UTF-8 {}", query);
+                parse_sort(&session.run_command_and_read_response(command)?)
+            } else {
+                let uids: Vec<u32> = session.uid_search(query)?.into_iter().collect();
+                let mut dated = vec![];
+                for batch in uids.chunks(LIST_BATCH) {
+                    let fetches = session.uid_fetch(uid_set(batch), "(UID INTERNALDATE)")?;
+                    dated.extend(
+                        fetches
+                            .iter()
+                            .filter_map(|f| Some((f.internal_date(), f.uid?))),
+                    );
+                }
+                // messages without a date go first, then by date, then by UID
+                dated.sort();
+                dated.into_iter().map(|(_, uid)| uid).collect()
+            };
+            if newest_first {
+                uids.reverse();
+            }
+            Ok(uids)
+        })
+    }
+
+    /// Envelopes of messages, in the order of `uids`, fetched in batches.
+    pub fn summaries(&mut self, uids: &[u32]) -> BlockingResult<Vec<Summary>> {
+        let mut by_uid = HashMap::with_capacity(uids.len());
+        for batch in uids.chunks(LIST_BATCH) {
+            self.run_idempotent(|session| {
+                let fetches = session.uid_fetch(
+                    uid_set(batch),
+                    "(UID ENVELOPE FLAGS INTERNALDATE RFC822.SIZE)",
+                )?;
+                for fetch in fetches.iter() {
+                    if let Some(uid) = fetch.uid {
+                        by_uid.insert(uid, summary(uid, fetch));
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(uids.iter().filter_map(|uid| by_uid.remove(uid)).collect())
+    }
+
+    /// Messages with the given UIDs in one command, in server order.
+    /// Messages that are gone are missing. `peek` leaves them unseen,
+    /// and only then is the command retried after a disconnect.
+    pub fn fetch_uids(&mut self, uids: &[u32], peek: bool) -> BlockingResult<Vec<Email>> {
+        if uids.is_empty() {
+            return Ok(vec![]);
+        }
+        let items = if peek {
+            "(UID INTERNALDATE BODY.PEEK[])"
+        } else {
+            "(UID INTERNALDATE BODY[])"
+        };
+        let op = |session: &mut ImapSession| {
+            let fetches = session.uid_fetch(uid_set(uids), items)?;
+            let mut emails = vec![];
+            for fetch in fetches.iter() {
+                let Some(uid) = fetch.uid else { continue };
+                let body = fetch.body().ok_or("No message content")?;
+                let mut email: Email = mailparse::parse_mail(body)?.into();
+                email.uid = Some(uid);
+                email.received = fetch.internal_date().map(|d| d.with_timezone(&Utc));
+                emails.push(email);
+            }
+            Ok(emails)
+        };
+        if peek {
+            self.run_idempotent(op)
+        } else {
+            self.run(op)
+        }
+    }
+
+    /// Original RFC822 source of a message, `None` when it is gone. Leaves it unseen.
+    pub fn fetch_raw(&mut self, uid: u32) -> BlockingResult<Option<Vec<u8>>> {
+        self.run_idempotent(|session| {
+            let fetches = session.uid_fetch(uid.to_string(), "(UID BODY.PEEK[])")?;
+            let Some(fetch) = fetches.iter().find(|f| f.uid == Some(uid)) else {
+                return Ok(None);
+            };
+            Ok(Some(fetch.body().ok_or("No message content")?.to_vec()))
+        })
+    }
+
+    /// Flag messages as seen, in batches.
+    pub fn mark_seen(&mut self, uids: &[u32]) -> BlockingResult<()> {
+        for batch in uids.chunks(LIST_BATCH) {
+            self.run(|session| {
+                session.uid_store(uid_set(batch), "+FLAGS (\\Seen)")?;
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl MessageSource for Mailbox {
+    fn sorted_uids(&mut self, query: &str, newest_first: bool) -> BlockingResult<Vec<u32>> {
+        Mailbox::sorted_uids(self, query, newest_first)
+    }
+
+    fn fetch_uids(&mut self, uids: &[u32], peek: bool) -> BlockingResult<Vec<Email>> {
+        Mailbox::fetch_uids(self, uids, peek)
+    }
+}
+
+fn summary(uid: u32, fetch: &Fetch) -> Summary {
+    let envelope = fetch.envelope();
+    let from = envelope
+        .and_then(|e| e.from.as_ref())
+        .and_then(|from| from.first())
+        .map(|addr| {
+            let text =
+                |part: Option<&[u8]>| String::from_utf8_lossy(part.unwrap_or_default()).to_string();
+            let email = format!("{}@{}", text(addr.mailbox), text(addr.host));
+            match addr.name {
+                Some(name) => format!("{} <{}>", decode_words(name), email),
+                None => email,
+            }
+        })
+        .unwrap_or_default();
+    Summary {
+        uid,
+        received: fetch.internal_date().map(|d| d.with_timezone(&Utc)),
+        from,
+        subject: envelope
+            .and_then(|e| e.subject)
+            .map(decode_words)
+            .unwrap_or_default(),
+        size: fetch.size,
+        flags: fetch.flags().iter().map(flag_name).collect(),
+    }
+}
+
+/// Decode RFC 2047 encoded words, as in `=?utf-8?q?caf=C3=A9?=`.
+pub(crate) fn decode_words(raw: &[u8]) -> String {
+    let mut line = b"X: ".to_vec();
+    line.extend_from_slice(raw);
+    match mailparse::parse_header(&line) {
+        Ok((header, _)) => header.get_value(),
+        Err(_) => String::from_utf8_lossy(raw).to_string(),
+    }
+}
+
+/// Lowercase name of a system flag without its `\\`, custom flags as they are.
+pub(crate) fn flag_name(flag: &Flag) -> String {
+    match flag {
+        Flag::Seen => "seen".to_string(),
+        Flag::Answered => "answered".to_string(),
+        Flag::Flagged => "flagged".to_string(),
+        Flag::Deleted => "deleted".to_string(),
+        Flag::Draft => "draft".to_string(),
+        Flag::Recent => "recent".to_string(),
+        Flag::Custom(name) => name.trim_start_matches('\\').to_string(),
+        other => other.to_string().trim_start_matches('\\').to_lowercase(),
+    }
+}
+
+/// UIDs of the untagged `* SORT` responses.
+pub(crate) fn parse_sort(response: &[u8]) -> Vec<u32> {
+    String::from_utf8_lossy(response)
+        .lines()
+        .filter_map(|line| line.strip_prefix("* SORT"))
+        .flat_map(|uids| uids.split_whitespace().filter_map(|uid| uid.parse().ok()))
+        .collect()
+}
+
+/// Messages with `uids` in batches of [`FETCH_BATCH`], without marking them seen.
+/// Messages that cannot be parsed are skipped.
+fn peek_batches(session: &mut ImapSession, uids: &[u32]) -> BlockingResult<Vec<Email>> {
+    let mut emails = vec![];
+    for batch in uids.chunks(FETCH_BATCH) {
+        let fetches = session.uid_fetch(uid_set(batch), "(UID INTERNALDATE BODY.PEEK[])")?;
+        for fetch in fetches.iter() {
+            if let Some(Ok(parsed)) = fetch.body().map(mailparse::parse_mail) {
+                let mut email: Email = parsed.into();
+                email.uid = fetch.uid;
+                email.received = fetch.internal_date().map(|d| d.with_timezone(&Utc));
+                emails.push(email);
+            }
+        }
+    }
+    Ok(emails)
+}
+
+/// UID set of a command, consecutive UIDs compressed into `a:b` ranges.
+pub(crate) fn uid_set(uids: &[u32]) -> String {
+    let mut uids = uids.to_vec();
+    uids.sort_unstable();
+    uids.dedup();
+    let mut ranges: Vec<String> = vec![];
+    let mut i = 0;
+    while i < uids.len() {
+        let start = uids[i];
+        while i + 1 < uids.len() && uids[i + 1] == uids[i] + 1 {
+            i += 1;
+        }
+        ranges.push(match uids[i] {
+            end if end == start => start.to_string(),
+            end => format!("{}:{}", start, end),
+        });
+        i += 1;
+    }
+    ranges.join(",")
+}
+
+/// Whether the connection is gone and reconnecting may help.
+pub(crate) fn is_disconnect(e: &(dyn Error + 'static)) -> bool {
+    matches!(
+        e.downcast_ref::<imap::error::Error>(),
+        Some(imap::error::Error::Io(_) | imap::error::Error::ConnectionLost)
+    )
+}
+
+/// Run `attempt` again, once, when it failed because the connection was lost.
+pub(crate) fn retry_once<T>(mut attempt: impl FnMut() -> BlockingResult<T>) -> BlockingResult<T> {
+    match attempt() {
+        Err(e) if is_disconnect(e.as_ref()) => {
+            warn!("Connection lost, reconnecting: {}", e);
+            attempt()
+        }
+        result => result,
+    }
+}
+
+/// Idle [`Mailbox`]es per account, shared by concurrent operations.
+/// Operations run on tokio blocking threads, each on its own session.
+#[derive(Clone, Default)]
+pub struct Pool {
+    idle: Arc<Mutex<HashMap<String, Vec<Mailbox>>>>,
+}
+
+impl Pool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pool used by the methods of [`EmailConfig`].
+    pub fn global() -> &'static Pool {
+        static POOL: OnceLock<Pool> = OnceLock::new();
+        POOL.get_or_init(Pool::new)
+    }
+
+    /// Run `op` with an idle mailbox of `account`, or a new one,
+    /// and keep the mailbox for later when it is still connected.
+    pub async fn run<T, F>(&self, account: &EmailConfig, op: F) -> Result<T, Box<dyn Error>>
+    where
+        F: FnOnce(&mut Mailbox) -> BlockingResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut mailbox = self.take(account);
+        let (mailbox, result) = tokio::task::spawn_blocking(move || {
+            let result = op(&mut mailbox);
+            (mailbox, result)
+        })
+        .await?;
+        self.put_back(mailbox);
+        result.map_err(|e| e as Box<dyn Error>)
+    }
+
+    /// Log out of every idle mailbox.
+    pub async fn close(&self) {
+        let idle: Vec<Mailbox> = self.lock().drain().flat_map(|(_, m)| m).collect();
+        let _ = tokio::task::spawn_blocking(move || {
+            for mut mailbox in idle {
+                let _ = mailbox.close();
+            }
+        })
+        .await;
+    }
+
+    /// Take an idle mailbox of `account`, or a new one. Give it back with [`Pool::put_back`].
+    pub fn take(&self, account: &EmailConfig) -> Mailbox {
+        self.lock()
+            .get_mut(&key(account))
+            .and_then(Vec::pop)
+            .unwrap_or_else(|| Mailbox::new(account.clone()))
+    }
+
+    /// Keep `mailbox` for later use when it is still connected.
+    pub fn put_back(&self, mailbox: Mailbox) {
+        if !mailbox.is_connected() {
+            return;
+        }
+        let mut idle = self.lock();
+        let mailboxes = idle.entry(key(mailbox.account())).or_default();
+        if mailboxes.len() < MAX_IDLE {
+            mailboxes.push(mailbox);
+        }
+    }
+
+    /// Number of idle mailboxes of `account`.
+    pub fn idle(&self, account: &EmailConfig) -> usize {
+        self.lock().get(&key(account)).map_or(0, Vec::len)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Vec<Mailbox>>> {
+        // a panic in another operation leaves the map usable
+        self.idle.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Mailboxes are shared only between accounts with the same login,
+/// server and password, the password is hashed to keep it out of the key.
+pub(crate) fn key(account: &EmailConfig) -> String {
+    let mut password = DefaultHasher::new();
+    account.pwd.hash(&mut password);
+    format!(
+        "{}@{}#{:016x}",
+        account.email,
+        account.smtp,
+        password.finish()
+    )
+}
@@ -1,11 +1,72 @@
+use chrono::NaiveDate;
 use clap::{Parser, Subcommand};
 use colored::*;
+use futures::StreamExt;
+use mail_sitter::aliases::{AliasDetails, AliasRecord, LeakPolicy, Registry};
+use mail_sitter::config::{self, ConfigFile};
 #[cfg(feature = "ddep")]
 use mail_sitter::ddep;
 use mail_sitter::email;
+use mail_sitter::mailbox;
+use mail_sitter::otp;
+use mail_sitter::providers::{self, ProviderKind};
+use mail_sitter::utils::output::{log_level, ColorChoice};
+use mail_sitter::view::{self, human_size, truncate};
 use mail_sitter::Config;
+use serde::Serialize;
 use std::error::Error;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, IsTerminal};
+use std::pin::pin;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Exit code of `wait` when logging in or reading the mailbox failed
+const EXIT_MAILBOX: i32 = 3;
+/// Exit code of `wait` when the criteria are invalid, e.g. a bad regex (EX_USAGE)
+const EXIT_CRITERIA: i32 = 64;
+/// Exit code of `wait` when the config cannot be read or has no such account (EX_CONFIG)
+const EXIT_CONFIG: i32 = 78;
+/// Exit code of `wait` when no email matched in time, as `timeout` does
+const EXIT_TIMEOUT: i32 = 124;
+
+/// Error ending the process with a given exit code, instead of 1.
+#[derive(Debug)]
+struct Exit {
+    code: i32,
+    error: Box<dyn Error>,
+}
+
+impl Exit {
+    fn with(code: i32) -> impl FnOnce(Box<dyn Error>) -> Box<dyn Error> {
+        move |error| Box::new(Exit { code, error })
+    }
+}
+
+impl std::fmt::Display for Exit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl Error for Exit {}
+
+/// Retries on rate limited requests before giving up
+#[cfg(feature = "ddep")]
+const MAX_RETRIES: u32 = 3;
+
+/// `--interval` in seconds, positive and finite as sleeping takes a `Duration`.
+fn parse_interval(arg: &str) -> Result<Duration, String> {
+    let secs: f64 = arg.parse().map_err(|e| format!("{}", e))?;
+    if !secs.is_finite() || secs <= 0.0 {
+        return Err(format!(
+            "expected a positive number of seconds, got `{}`",
+            arg
+        ));
+    }
+    Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())
+}
 
 /// Simple email reader
 #[derive(Parser, Debug)]
@@ -17,10 +78,40 @@ use std::io::{self, BufRead};
     about = "A email tool set"
 )]
 struct Args {
+    /// Print more diagnostics, -vv for a trace of everything mail sitter does
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Print fewer diagnostics: -q only warnings, -qq only errors, -qqq nothing
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    quiet: u8,
+
+    /// When to colour the output, `auto` colours terminals unless NO_COLOR is set
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Trace the IMAP and HTTP traffic on stderr, with credentials redacted
+    #[arg(long, global = true)]
+    trace: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Whether stderr is coloured, set in `main`. Prompts go to stderr,
+/// which may be a terminal when stdout is piped.
+static STDERR_COLOR: AtomicBool = AtomicBool::new(false);
+
+/// `text` to print on stderr, coloured only when stderr is.
+fn on_stderr(text: ColoredString) -> String {
+    match text.fgcolor {
+        Some(color) if STDERR_COLOR.load(Ordering::Relaxed) => {
+            format!("\x1b[{}m{}\x1b[0m", color.to_fg_str(), &*text)
+        }
+        _ => String::from(&*text),
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Initialize the configuration file
@@ -47,32 +138,430 @@ enum Commands {
         /// Path to save the configuration file
         #[arg(long, default_value_t = config_path_default())]
         path: String,
+
+        /// Replace the configuration file instead of merging into it
+        #[arg(long)]
+        replace: bool,
     },
-    #[cfg(feature = "ddep")]
+    /// Log in to an alias provider
     Login {
+        /// Alias provider: duckduckgo, simplelogin, addy or relay
+        #[arg(long, default_value = "duckduckgo")]
+        provider: ProviderKind,
+
         /// Username of duckduckgo email protection services,
         /// this feature require compile with flag --features ddep
         #[arg(long)]
-        username: String,
+        username: Option<String>,
+
+        /// API key of simplelogin, addy or relay
+        #[arg(long)]
+        api_key: Option<String>,
+
+        /// API base URL of the provider, for self hosted instances or testing
+        #[arg(long)]
+        base_url: Option<String>,
 
         /// Path of the configuration file
         #[arg(long, default_value_t = config_path_default())]
         path: String,
+
+        /// Email account receiving the login email, defaults to the first account
+        #[arg(long)]
+        account: Option<String>,
+
+        /// Request the duckduckgo login email, then prompt for the passphrase
+        /// or link in it instead of reading it over IMAP
+        #[arg(long)]
+        manual: bool,
+
+        /// One-time passphrase or login link of duckduckgo, requested beforehand
+        #[arg(long, conflicts_with = "manual")]
+        otp: Option<String>,
+
+        /// Only request the duckduckgo login email and exit, then log in with --otp
+        #[arg(long, conflicts_with_all = ["manual", "otp"])]
+        request_otp: bool,
     },
     /// Read emails using the configuration file
     Fetch {
         /// Path to the configuration file
         #[arg(short, long, default_value_t = config_path_default())]
         config: String,
+
+        /// Email account to read, defaults to the first account
+        #[arg(long)]
+        account: Option<String>,
+
+        /// Read at most this many emails
+        #[arg(long)]
+        limit: Option<usize>,
+
+        /// Only emails received on or after this day, as YYYY-MM-DD
+        #[arg(long)]
+        since: Option<NaiveDate>,
+
+        /// Only emails received before this day, as YYYY-MM-DD
+        #[arg(long)]
+        before: Option<NaiveDate>,
+
+        /// Show the latest emails first
+        #[arg(long)]
+        newest_first: bool,
+
+        /// Read all emails, not only the unread ones
+        #[arg(long, conflicts_with = "unseen")]
+        all: bool,
+
+        /// Only read unread emails, the default
+        #[arg(long)]
+        unseen: bool,
+
+        /// Print each email with a template, e.g. '{date} {from.name} {subject}',
+        /// or the name of a template in the config
+        #[arg(long)]
+        format: Option<String>,
     },
 
-    /// Get alias from duckduckgo email protection
-    #[cfg(feature = "ddep")]
+    /// List emails quickly, from their envelopes only
+    List {
+        /// Path to the configuration file
+        #[arg(short, long, default_value_t = config_path_default())]
+        config: String,
+
+        /// Email account to read, defaults to the first account
+        #[arg(long)]
+        account: Option<String>,
+
+        /// List at most this many emails
+        #[arg(long)]
+        limit: Option<usize>,
+
+        /// Only emails received on or after this day, as YYYY-MM-DD
+        #[arg(long)]
+        since: Option<NaiveDate>,
+
+        /// Only emails received before this day, as YYYY-MM-DD
+        #[arg(long)]
+        before: Option<NaiveDate>,
+
+        /// Show the latest emails first
+        #[arg(long)]
+        newest_first: bool,
+
+        /// Only list unread emails
+        #[arg(long)]
+        unseen: bool,
+
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show one email, by the UID `list` prints, without marking it as read
+    Show {
+        /// Path to the configuration file
+        #[arg(short, long, default_value_t = config_path_default())]
+        config: String,
+
+        /// Email account to read, defaults to the first account
+        #[arg(long)]
+        account: Option<String>,
+
+        /// UID of the email
+        uid: u32,
+
+        /// Print the original message source
+        #[arg(long, conflicts_with_all = ["headers", "part"])]
+        raw: bool,
+
+        /// Print every header, with encoded words decoded
+        #[arg(long, conflicts_with = "part")]
+        headers: bool,
+
+        /// Print only the n-th MIME part, counting from 1 as listed under `Parts`
+        #[arg(long)]
+        part: Option<usize>,
+
+        /// Print the email with a template, or the name of a template in the config
+        #[arg(long, conflicts_with_all = ["raw", "headers", "part"])]
+        format: Option<String>,
+
+        /// Print to stdout even when $PAGER is set
+        #[arg(long)]
+        no_pager: bool,
+    },
+    /// Wait for a verification code or link from a sender and print it
+    Otp {
+        /// Path to the configuration file
+        #[arg(short, long, default_value_t = config_path_default())]
+        config: String,
+
+        /// Email account to read, defaults to the first account
+        #[arg(long)]
+        account: Option<String>,
+
+        /// Sender address or domain
+        #[arg(long)]
+        from: String,
+
+        /// Seconds to wait for the email
+        #[arg(long, default_value_t = 60)]
+        wait: u64,
+
+        /// Seconds between two checks of the inbox
+        #[arg(long, default_value = "2", value_parser = parse_interval)]
+        interval: Duration,
+
+        /// Only accept codes of this kind: numeric, alphanumeric, passphrase or link
+        #[arg(long)]
+        kind: Option<otp::CodeKind>,
+    },
+    /// Block until an email matches. Exits 0 when one matched, 124 on timeout,
+    /// 3 when the mailbox cannot be reached, 64 on invalid criteria and 78 on config errors
+    Wait {
+        /// Path to the configuration file
+        #[arg(short, long, default_value_t = config_path_default())]
+        config: String,
+
+        /// Email account to read, defaults to the first account
+        #[arg(long)]
+        account: Option<String>,
+
+        /// Text the From header contains
+        #[arg(long)]
+        from: Option<String>,
+
+        /// Text the subject contains
+        #[arg(long)]
+        subject: Option<String>,
+
+        /// Regex the body matches
+        #[arg(long)]
+        body_regex: Option<String>,
+
+        /// Ignore mail already in the inbox
+        #[arg(long)]
+        since_now: bool,
+
+        /// Seconds to wait
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+
+        /// Seconds between two checks of the inbox
+        #[arg(long, default_value = "2", value_parser = parse_interval)]
+        interval: Duration,
+    },
+    /// Get alias from duckduckgo email protection, or another provider
     Address {
         /// Path to the configuration file
         #[arg(short, long, default_value_t = config_path_default())]
         config: String,
+
+        /// Alias provider: duckduckgo, simplelogin, addy or relay
+        #[arg(long, default_value = "duckduckgo")]
+        provider: ProviderKind,
+
+        /// Provider account to generate the alias for, defaults to the first one
+        #[arg(long, visible_alias = "username")]
+        account: Option<String>,
+
+        /// Email account receiving the duckduckgo login passphrase, defaults to the first one
+        #[arg(long)]
+        email_account: Option<String>,
+
+        /// Path to the alias registry
+        #[arg(long, default_value_t = aliases_path_default())]
+        registry: String,
+
+        /// Label recorded with the alias, e.g. the vendor it is given to
+        #[arg(long)]
+        label: Option<String>,
+
+        /// Site the alias is used on
+        #[arg(long)]
+        site: Option<String>,
+
+        /// Note recorded with the alias
+        #[arg(long)]
+        note: Option<String>,
+    },
+
+    /// Browse and annotate the registry of generated aliases
+    Aliases {
+        /// Path to the alias registry
+        #[arg(long, default_value_t = aliases_path_default())]
+        registry: String,
+
+        #[command(subcommand)]
+        action: AliasesAction,
+    },
+
+    /// DuckDuckGo email protection account
+    #[cfg(feature = "ddep")]
+    Ddg {
+        /// Path to the configuration file
+        #[arg(short, long, default_value_t = config_path_default())]
+        config: String,
+
+        #[command(subcommand)]
+        action: DdgAction,
+    },
+
+    /// Disposable inboxes: generate an alias and wait for the mail sent to it
+    Inbox {
+        /// Path to the configuration file
+        #[arg(short, long, default_value_t = config_path_default())]
+        config: String,
+
+        #[command(subcommand)]
+        action: InboxAction,
+    },
+
+    /// Read and modify the configuration file
+    Config {
+        /// Path to the configuration file
+        #[arg(short, long, default_value_t = config_path_default())]
+        config: String,
+
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AliasesAction {
+    /// List all recorded aliases
+    List,
+    /// Find aliases by address, label, site or note
+    Search {
+        /// Case insensitive text to look for
+        query: String,
+    },
+    /// Change the label or site of an alias, or add a note
+    Annotate {
+        /// Alias address, with or without the domain
+        address: String,
+        #[arg(long)]
+        label: Option<String>,
+        #[arg(long)]
+        site: Option<String>,
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Scan new mail for the aliases it was sent to, and flag leaked aliases
+    Audit {
+        /// Path to the configuration file
+        #[arg(short, long, default_value_t = config_path_default())]
+        config: String,
+
+        /// Email account receiving the forwarded mail, defaults to the first account
+        #[arg(long)]
+        account: Option<String>,
+
+        /// Number of unexpected sender domains tolerated before flagging
+        #[arg(long, default_value_t = 0)]
+        tolerate_domains: usize,
+
+        /// Number of spam messages tolerated before flagging
+        #[arg(long, default_value_t = 0)]
+        tolerate_spam: u64,
+    },
+}
+
+#[cfg(feature = "ddep")]
+#[derive(Subcommand, Debug)]
+enum DdgAction {
+    /// Show the forwarding address, alias count and token validity
+    Status {
+        /// Username, defaults to the first one
+        #[arg(long)]
+        username: Option<String>,
+
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum InboxAction {
+    /// Generate an alias, record it and print only its address
+    New {
+        /// Alias provider: duckduckgo, simplelogin, addy or relay
+        #[arg(long, default_value = "duckduckgo")]
+        provider: ProviderKind,
+
+        /// Provider account to generate the alias for, defaults to the first one
+        #[arg(long, visible_alias = "username")]
+        account: Option<String>,
+
+        /// Email account receiving the duckduckgo login passphrase, defaults to the first one
+        #[arg(long)]
+        email_account: Option<String>,
+
+        /// Path to the alias registry
+        #[arg(long, default_value_t = aliases_path_default())]
+        registry: String,
+
+        /// Label recorded with the alias
+        #[arg(long)]
+        label: Option<String>,
+    },
+    /// Wait for the first mail sent to an alias from now on, and print it as JSON
+    Wait {
+        /// Alias address
+        alias: String,
+
+        /// Email account receiving the forwarded mail, defaults to the first account
+        #[arg(long)]
+        account: Option<String>,
+
+        /// Seconds to wait for the mail
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+
+        /// Seconds between two checks of the inbox
+        #[arg(long, default_value = "2", value_parser = parse_interval)]
+        interval: Duration,
+
+        /// Only extract codes of this kind: numeric, alphanumeric, passphrase or link
+        #[arg(long)]
+        kind: Option<otp::CodeKind>,
+    },
+}
+
+/// Output of `inbox wait`
+#[derive(Serialize)]
+struct InboxMail<'a> {
+    alias: &'a str,
+    email: &'a email::Email,
+    /// OTP or link found in the mail
+    code: Option<otp::Code>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Print the value of a dotted key, e.g. `accounts.0.smtp`
+    Get {
+        /// Dotted key
+        key: String,
+    },
+    /// Set the value of a dotted key, the value is parsed as YAML
+    Set {
+        /// Dotted key
+        key: String,
+        /// New value
+        value: String,
     },
+    /// Remove a dotted key from the configuration file
+    Unset {
+        /// Dotted key
+        key: String,
+    },
+    /// Open the configuration file in $EDITOR
+    Edit,
+    /// Print the JSON Schema of the configuration file
+    Schema,
 }
 
 fn config_path_default() -> String {
@@ -84,47 +573,616 @@ fn config_path_default() -> String {
         .to_string()
 }
 
-#[cfg(feature = "ddep")]
-async fn login_ddep(u: String, config: email::EmailConfig) -> Result<(), Box<dyn Error>> {
-    let mut client = ddep::Client::new(u.clone(), None, None);
-    println!("{}", "Getting OTP...".cyan());
-    if let Err(_) = client.otp(None).await {
-        println!("{}", "DuckDuckGo thinks you are a bot.".red());
+fn aliases_path_default() -> String {
+    dirs::home_dir()
+        .unwrap()
+        .join(".mailsitter")
+        .join("aliases")
+        .to_string_lossy()
+        .to_string()
+}
+
+fn print_summaries(summaries: &[email::Summary]) {
+    println!(
+        "{}",
+        format!(
+            "{:>7}  {:<16}  {:<28}  {:<48}  {:>6}  {}",
+            "UID", "DATE", "FROM", "SUBJECT", "SIZE", "FLAGS"
+        )
+        .bold()
+    );
+    for s in summaries {
+        let date = s
+            .received
+            .map(|d| {
+                d.with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        let line = format!(
+            "{:>7}  {:<16}  {:<28}  {:<48}  {:>6}  {}",
+            s.uid,
+            date,
+            truncate(&s.from, 28),
+            truncate(&s.subject, 48),
+            s.size
+                .map(|size| human_size(size.into()))
+                .unwrap_or_default(),
+            s.flags.join(",")
+        );
+        if s.flags.iter().any(|f| f == "seen") {
+            println!("{}", line);
+        } else {
+            println!("{}", line.bold());
+        }
+    }
+}
+
+/// Print `text` through $PAGER when stdout is a terminal, directly otherwise.
+fn page(text: &str, no_pager: bool) -> Result<(), Box<dyn Error>> {
+    use std::io::Write;
+    let pager = std::env::var("PAGER").ok().filter(|p| !p.trim().is_empty());
+    let Some(pager) = pager.filter(|_| !no_pager && io::stdout().is_terminal()) else {
+        print!("{}", text);
+        return Ok(());
+    };
+    // $PAGER is a shell command, as for git and man
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(&pager)
+        .stdin(std::process::Stdio::piped());
+    if std::env::var_os("LESS").is_none() {
+        // keep colours and quit when the text fits on one screen, as git does
+        cmd.env("LESS", "FRX");
+    }
+    let mut child = cmd.spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // the pager closing early is not an error
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    child.wait()?;
+    Ok(())
+}
+
+fn print_alias(alias: &AliasRecord) {
+    println!(
+        "{} {} {}",
+        alias.address.red(),
+        alias.label.as_deref().unwrap_or("-").cyan(),
+        alias.site.as_deref().unwrap_or("-").blue()
+    );
+    println!(
+        "    {} via {} ({})",
+        alias.created_at.format("%Y-%m-%d %H:%M"),
+        alias.provider,
+        alias.account
+    );
+    for note in &alias.notes {
+        println!("    - {}", note);
+    }
+    if !alias.stats.is_empty() {
         println!(
+            "    {} messages from {} senders, last on {}",
+            alias.stats.messages,
+            alias.stats.senders.len(),
+            alias
+                .stats
+                .last_seen
+                .map(|d| d.format("%Y-%m-%d").to_string())
+                .unwrap_or_default()
+        );
+    }
+    if let Some(leaked) = alias.leaked {
+        println!(
+            "    {}",
+            format!("leaked, flagged on {}", leaked.format("%Y-%m-%d")).red()
+        );
+    }
+}
+
+async fn audit_aliases(
+    path: &str,
+    account: &email::EmailConfig,
+    policy: LeakPolicy,
+) -> Result<(), Box<dyn Error>> {
+    // only where to start fetching, the watermark is advanced under the lock
+    let since = Registry::read_or_default(path)?
+        .audited
+        .get(&account.email)
+        .copied();
+    info!("Fetching new emails...");
+    let (watermark, emails) = account.fetch_new(since).await?;
+    let (counted, flagged, registry) = Registry::update(path, |registry| {
+        let counted = registry.audit(&account.email, watermark, &emails);
+        let flagged: Vec<String> = registry
+            .flag_leaks(&policy)
+            .into_iter()
+            .map(|a| a.address.clone())
+            .collect();
+        Ok((counted, flagged, registry.aliases.clone()))
+    })?;
+    println!("{} new emails audited\n", counted);
+    registry
+        .iter()
+        .filter(|a| !a.stats.is_empty())
+        .for_each(print_alias);
+    for alias in registry.iter().filter(|a| flagged.contains(&a.address)) {
+        println!(
+            "{} {}: {}",
+            "Leaked:".red(),
+            alias.address,
+            alias.leak_reasons(&policy).join("; ")
+        );
+    }
+    Ok(())
+}
+
+async fn parse_aliases_cmd(path: &str, action: AliasesAction) -> Result<(), Box<dyn Error>> {
+    match action {
+        AliasesAction::List => {
+            let registry = Registry::read_or_default(path)?;
+            registry.aliases.iter().for_each(print_alias);
+        }
+        AliasesAction::Search { query } => {
+            let registry = Registry::read_or_default(path)?;
+            registry.search(&query).for_each(print_alias);
+        }
+        AliasesAction::Annotate {
+            address,
+            label,
+            site,
+            note,
+        } => {
+            let alias = Registry::update(path, |registry| {
+                registry.annotate(&address, label, site, note).cloned()
+            })?;
+            print_alias(&alias);
+        }
+        AliasesAction::Audit {
+            config,
+            account,
+            tolerate_domains,
+            tolerate_spam,
+        } => {
+            let cfg = ConfigFile::read(&config)?;
+            let policy = LeakPolicy {
+                tolerated_domains: tolerate_domains,
+                tolerated_spam: tolerate_spam,
+            };
+            audit_aliases(path, cfg.account(account.as_deref())?, policy).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Where the one-time passphrase of a duckduckgo login comes from
+#[cfg(feature = "ddep")]
+enum Otp<'a> {
+    /// Read from the login email in this account
+    Mail(&'a email::EmailConfig),
+    /// Pasted by the user
+    Prompt,
+    /// Already requested and given on the command line
+    Given(String),
+}
+
+#[cfg(feature = "ddep")]
+async fn login_ddep(
+    u: String,
+    otp: Otp<'_>,
+    path: &str,
+    base_url: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let mut client = ddep::Client::new(u.clone(), None, None, base_url);
+    if let Otp::Given(otp) = otp {
+        client.full_login(&otp, None).await?;
+        info!("Got token!");
+        return save_ddep(path, client);
+    }
+    // taken before the request, so only the email it triggers matches
+    let otp_mail = match otp {
+        Otp::Mail(config) => Some((config, ddep::otp_mail_filter(config).await?)),
+        _ => None,
+    };
+    info!("Getting OTP...");
+    match request_otp_mail(&client).await {
+        Ok(_) => {}
+        Err(ddep::DdgError::BotChallenge) => {
+            eprintln!("{}", on_stderr("DuckDuckGo thinks you are a bot.".red()));
+            eprintln!(
+                "{}",
+                on_stderr("We need you to verify your identity by logging in once.".yellow())
+            );
+            eprintln!("{}", on_stderr(
+                "Please click [here](https://duckduckgo.com/email/login) to log in to your email, and then return here."
+                .blue()
+            ));
+            #[cfg(not(feature = "gui"))]
+            {
+                let _ = mail_sitter::utils::browser::open("https://duckduckgo.com/email/login");
+                eprintln!("{}", on_stderr("please press Enter to continue...".green()));
+                let stdin = io::stdin();
+                let _ = stdin.lock().lines().next();
+            }
+            info!("Continuing...");
+        }
+        Err(e) => return Err(e.into()),
+    }
+    if let Some((config, filter)) = otp_mail {
+        info!("Checking latest login email");
+        ddep::complete_login_via_mail(&mut client, config, &filter).await?;
+    } else {
+        eprintln!(
             "{}",
-            "We need you to verify your identity by logging in once.".yellow()
+            on_stderr("Paste the one-time passphrase or the login link from the email:".yellow())
         );
-        println!("{}",
-            "Please click [here](https://duckduckgo.com/email/login) to log in to your email, and then return here."
-            .blue()
+        let line = io::stdin()
+            .lock()
+            .lines()
+            .next()
+            .ok_or("no passphrase given")??;
+        client.full_login(line.trim(), None).await?;
+    }
+    info!("Got token!");
+    save_ddep(path, client)?;
+    Ok(())
+}
+
+/// Ask duckduckgo to mail the one-time passphrase, backing off when rate limited.
+#[cfg(feature = "ddep")]
+async fn request_otp_mail(client: &ddep::Client) -> Result<(), ddep::DdgError> {
+    let mut attempt = 0;
+    loop {
+        match client.otp(None).await {
+            Err(e) if attempt < MAX_RETRIES && e.retry_delay(attempt).is_some() => {
+                let delay = e.retry_delay(attempt).unwrap_or_default();
+                warn!("{}, backing off...", e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result.map(|_| ()),
+        }
+    }
+}
+
+#[cfg(feature = "ddep")]
+fn save_ddep(path: &str, client: ddep::Client) -> Result<(), Box<dyn Error>> {
+    let user: ddep::DdConfig = client.into();
+    ConfigFile::update(path, |cfg| {
+        cfg.upsert_duckduckgo(user);
+        Ok(())
+    })
+}
+
+/// Check the duckduckgo token before using it, backing off when rate limited, and
+/// log in again when it expired.
+#[cfg(feature = "ddep")]
+async fn revalidate_ddep(
+    config: &str,
+    user: Option<&str>,
+    email_account: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let file = ConfigFile::read(config)?;
+    let Some(user) = file.duckduckgo(user) else {
+        return Ok(());
+    };
+    let mut client: ddep::Client = user.clone().into();
+    let mut attempt = 0;
+    loop {
+        match client.validate_token().await {
+            Ok(true) if client.access_token != user.access_token => {
+                return save_ddep(config, client)
+            }
+            Ok(true) => return Ok(()),
+            Ok(false) => break,
+            Err(e) if attempt < MAX_RETRIES && e.retry_delay(attempt).is_some() => {
+                let delay = e.retry_delay(attempt).unwrap_or_default();
+                warn!("{}, backing off...", e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    warn!("{}, logging in again...", ddep::DdgError::Unauthorized);
+    relogin_ddep(config, &file, user, email_account).await
+}
+
+/// Log in to duckduckgo again as `user`, with the passphrase mailed to `email_account`,
+/// or pasted by the user when no email account is configured.
+#[cfg(feature = "ddep")]
+async fn relogin_ddep(
+    config: &str,
+    file: &ConfigFile,
+    user: &ddep::DdConfig,
+    email_account: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let otp = match email_account {
+        None if file.accounts.is_empty() => Otp::Prompt,
+        _ => Otp::Mail(file.account(email_account)?),
+    };
+    login_ddep(user.username.clone(), otp, config, user.base_url.clone()).await
+}
+
+/// Generate an alias and record it in `registry`, logging in to duckduckgo again
+/// when its token expired, and backing off when rate limited.
+#[cfg_attr(not(feature = "ddep"), allow(unused_variables))]
+async fn generate_address(
+    config: &str,
+    provider: ProviderKind,
+    account: Option<&str>,
+    email_account: Option<&str>,
+    registry: &str,
+    details: AliasDetails,
+) -> Result<AliasRecord, Box<dyn Error>> {
+    #[cfg(feature = "ddep")]
+    let (mut attempt, mut relogged) = (0, false);
+    loop {
+        let file = ConfigFile::read_or_default(config)?;
+        let client = providers::from_config(provider, &file, account)?;
+        let err = match client.generate_recorded(registry, details.clone()).await {
+            Ok(alias) => return Ok(alias),
+            Err(err) => err,
+        };
+        #[cfg(feature = "ddep")]
+        if let Some(e) = err.downcast_ref::<ddep::DdgError>() {
+            if let Some(delay) = e.retry_delay(attempt).filter(|_| attempt < MAX_RETRIES) {
+                warn!("{}, backing off...", e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                continue;
+            }
+            if e.needs_login() && !relogged {
+                warn!("{}", e);
+                let user = file
+                    .duckduckgo(account)
+                    .ok_or("no duckduckgo user in the config")?;
+                relogin_ddep(config, &file, user, email_account).await?;
+                relogged = true;
+                continue;
+            }
+        }
+        return Err(err);
+    }
+}
+
+#[cfg(feature = "ddep")]
+async fn parse_ddg_cmd(path: &str, action: DdgAction) -> Result<(), Box<dyn Error>> {
+    match action {
+        DdgAction::Status { username, json } => {
+            let file = ConfigFile::read(path)?;
+            let user = file
+                .duckduckgo(username.as_deref())
+                .ok_or("no duckduckgo user in the config, run `login` first")?;
+            let client: ddep::Client = user.clone().into();
+            let status = client.account_status().await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&status)?);
+                return Ok(());
+            }
+            let unknown = || "unknown".dimmed().to_string();
+            println!("{:<12}{}@duck.com", "username", status.username.green());
+            println!(
+                "{:<12}{}",
+                "forwarding",
+                status.forwarding.unwrap_or_else(unknown)
+            );
+            println!(
+                "{:<12}{}",
+                "aliases",
+                status
+                    .aliases
+                    .map(|n| n.to_string())
+                    .unwrap_or_else(unknown)
+            );
+            let token = if status.token_valid {
+                "valid".green()
+            } else {
+                "expired, run `login` again".red()
+            };
+            println!("{:<12}{}", "token", token);
+        }
+    }
+    Ok(())
+}
+
+async fn parse_inbox_cmd(path: &str, action: InboxAction) -> Result<(), Box<dyn Error>> {
+    match action {
+        InboxAction::New {
+            provider,
+            account,
+            email_account,
+            registry,
+            label,
+        } => {
+            #[cfg(feature = "ddep")]
+            if provider == ProviderKind::Duckduckgo {
+                revalidate_ddep(path, account.as_deref(), email_account.as_deref()).await?;
+            }
+            let details = AliasDetails {
+                label,
+                ..Default::default()
+            };
+            let alias = generate_address(
+                path,
+                provider,
+                account.as_deref(),
+                email_account.as_deref(),
+                &registry,
+                details,
+            )
+            .await?;
+            println!("{}", alias.address);
+        }
+        InboxAction::Wait {
+            alias,
+            account,
+            timeout,
+            interval,
+            kind,
+        } => {
+            if alias.contains('"') {
+                return Err(format!("Invalid alias `{}`", alias).into());
+            }
+            let cfg = ConfigFile::read(path)?;
+            let account = cfg.account(account.as_deref())?;
+            // only mail arriving from now on, an older one may be from another signup
+            let filter = email::MailFilter {
+                to: Some(alias.clone()),
+                after: Some(account.watermark().await?),
+                since: Some(chrono::Utc::now()),
+                ..Default::default()
+            };
+            let email = account
+                .wait_for(&filter, Duration::from_secs(timeout), interval)
+                .await?;
+            if let Some(uid) = email.uid {
+                account.mark_seen(&[uid]).await?;
+            }
+            let mail = InboxMail {
+                alias: &alias,
+                email: &email,
+                code: otp::extract(&email, &cfg.otp, kind),
+            };
+            println!("{}", serde_json::to_string_pretty(&mail)?);
+        }
+    }
+    Ok(())
+}
+/// Fail when `doc` would not load as a config, so it is not written.
+fn check_config(doc: &serde_yaml::Value) -> Result<(), Box<dyn Error>> {
+    ConfigFile::from_value(doc.clone())
+        .map(|_| ())
+        .map_err(|e| format!("Configuration left unchanged, it would be invalid: {}", e).into())
+}
+
+/// Open the config in `$EDITOR`, and only write it back once it parses.
+fn edit_config(path: &str) -> Result<(), Box<dyn Error>> {
+    // held for the whole edit, so a concurrent update is not overwritten
+    let _lock = config::lock(path)?;
+    let original = config::read_value(path)?;
+    let buffer = config::create_edit_buffer(path, serde_yaml::to_string(&original)?.as_bytes())?;
+    let result = edit_until_valid(&buffer).and_then(|value| config::write_value(path, &value));
+    let _ = std::fs::remove_file(&buffer);
+    result
+}
+
+/// Run the editor on `buffer` until it holds a valid config, or the user gives up.
+fn edit_until_valid(buffer: &std::path::Path) -> Result<serde_yaml::Value, Box<dyn Error>> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    loop {
+        let status = Command::new(&editor).arg(buffer).status()?;
+        if !status.success() {
+            return Err(format!("Editor `{}` exited with {}", editor, status).into());
+        }
+        let content = std::fs::read_to_string(buffer)?;
+        let checked = serde_yaml::from_str::<serde_yaml::Value>(&content)
+            .map_err(|e| e.to_string())
+            .and_then(|v| {
+                ConfigFile::from_value(v.clone())
+                    .map(|_| v)
+                    .map_err(|e| e.to_string())
+            });
+        match checked {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                eprintln!("{} {}", on_stderr("Invalid configuration:".red()), e);
+                eprintln!("{}", on_stderr("Edit again? [Y/n]".yellow()));
+                let input = io::stdin()
+                    .lock()
+                    .lines()
+                    .next()
+                    .unwrap_or(Ok(String::new()))?;
+                if input.trim().eq_ignore_ascii_case("n") {
+                    return Err("Configuration left unchanged".into());
+                }
+            }
+        }
+    }
+}
+
+fn parse_config_cmd(path: &str, action: ConfigAction) -> Result<(), Box<dyn Error>> {
+    match action {
+        ConfigAction::Get { key } => {
+            let value = config::read_value(path)?;
+            match config::get_key(&value, &key) {
+                Some(serde_yaml::Value::String(s)) => println!("{}", s),
+                Some(v) => print!("{}", serde_yaml::to_string(v)?),
+                None => return Err(format!("Key `{}` not found", key).into()),
+            }
+        }
+        ConfigAction::Set { key, value } => {
+            let _lock = config::lock(path)?;
+            let mut doc = config::read_value(path)?;
+            config::set_key(&mut doc, &key, config::parse_value(&value))?;
+            check_config(&doc)?;
+            config::write_value(path, &doc)?;
+        }
+        ConfigAction::Unset { key } => {
+            let _lock = config::lock(path)?;
+            let mut doc = config::read_value(path)?;
+            if config::unset_key(&mut doc, &key).is_none() {
+                return Err(format!("Key `{}` not found", key).into());
+            }
+            check_config(&doc)?;
+            config::write_value(path, &doc)?;
+        }
+        ConfigAction::Edit => edit_config(path)?,
+        ConfigAction::Schema => {
+            println!("{}", serde_json::to_string_pretty(&config::json_schema())?);
+        }
+    }
+    Ok(())
+}
+
+/// Guide the user through setting up duckduckgo email protection.
+#[cfg(feature = "ddep")]
+#[cfg_attr(feature = "gui", allow(unused_variables))]
+async fn setup_ddep(file: &ConfigFile, config: &str) -> Result<(), Box<dyn Error>> {
+    eprintln!(
+        "{}",
+        on_stderr("Config of duckduckgo email protection not found!".red())
+    );
+    eprintln!(
+        "{}",
+        on_stderr("You can regist the services from https://duckduckgo.com/email/start".blue())
+    );
+    eprintln!("{}", on_stderr("Or set username with init command".blue()));
+    #[cfg(not(feature = "gui"))]
+    {
+        eprintln!("{}", on_stderr("Open browser and visit site? [0]".yellow()));
+        eprintln!(
+            "{}",
+            on_stderr("Setup username of duckduckgo email protection services? [1]".yellow())
         );
-        #[cfg(not(feature = "gui"))]
-        {
-            let _ = mail_sitter::utils::browser::open("https://duckduckgo.com/email/login");
-            println!("{}", "please press Enter to continue...".green());
-            let stdin = io::stdin();
-            let _ = stdin.lock().lines().next();
-        }
-        println!("{}", "Continuing...".cyan());
-    }
-    println!("{}", "Checking latest login email".cyan());
-    let emails = config
-        .fetch_until(
-            "NOT OR OR NOT FROM support@duck.com NOT BODY \"one-time passphrase\" SEEN",
-            30,
-            0.5,
-        )
-        .await?;
-    if !emails.is_empty() {
-        let msg = &emails.last().unwrap().body;
-        if let Some(otp) = ddep::get_otp_via_mail(msg) {
-            client.full_login(otp.as_str(), None).await?;
-            println!("{}", "Got token!".green());
-            let ddep_path = config_path_default();
-            let config: ddep::DdConfig = client.into();
-            config.save(&ddep_path)?;
-        } else {
-            println!("{} {:?}", "Failed to parse email,".red(), &msg);
+        let stdin = io::stdin();
+        let input = {
+            stdin
+                .lock()
+                .lines()
+                .next()
+                .unwrap_or_else(|| Ok(String::from("")))?
+        };
+        match input.trim().to_lowercase().as_str() {
+            "0" => {
+                mail_sitter::utils::browser::open("https://duckduckgo.com/email/start")?;
+            }
+            "1" => {
+                eprintln!("{}", on_stderr("Your username: \n\n".cyan()));
+                let stdin = io::stdin();
+                let input = { stdin.lock().lines().next() };
+                if let Some(Ok(username)) = input {
+                    let username = username.trim();
+                    let email_cfg = file.account(None)?;
+                    login_ddep(username.to_string(), Otp::Mail(email_cfg), config, None).await?;
+                }
+            }
+            _ => {
+                eprintln!("{}", on_stderr("Exit.".yellow()));
+            }
         }
     }
     Ok(())
@@ -138,98 +1196,339 @@ async fn parse_cmd(cmd: Commands) -> Result<(), Box<dyn Error>> {
             smtp,
             path,
             username,
-            ..
+            replace,
         } => {
-            let config = email::EmailConfig::new(email, pwd, smtp);
-            email::EmailConfig::save(&config, &path)?;
+            let account = email::EmailConfig::new(email, pwd, smtp);
+            account.validate()?;
+            if replace {
+                let mut cfg = ConfigFile::default();
+                cfg.upsert_account(account.clone());
+                cfg.replace(&path)?;
+            } else {
+                ConfigFile::update(&path, |cfg| {
+                    cfg.upsert_account(account.clone());
+                    Ok(())
+                })?;
+            }
             #[cfg(feature = "ddep")]
             {
                 if let Some(u) = username {
-                    login_ddep(u, config).await?;
+                    login_ddep(u, Otp::Mail(&account), &path, None).await?;
                 }
             }
-            println!("{}", "Configuration initialized successfully!".green());
+            info!("Configuration initialized successfully!");
         }
-        #[cfg(feature = "ddep")]
-        Commands::Login { username, path } => {
-            let config: email::EmailConfig = email::EmailConfig::read(&path)?;
-            login_ddep(username, config).await?;
+        Commands::Login {
+            provider,
+            username,
+            api_key,
+            base_url,
+            path,
+            account,
+            manual,
+            otp,
+            request_otp,
+        } => match provider {
+            #[cfg(feature = "ddep")]
+            ProviderKind::Duckduckgo => {
+                let username = username.ok_or("--username is required to log in to duckduckgo")?;
+                let cfg = ConfigFile::read_or_default(&path)?;
+                let base_url = base_url.or_else(|| {
+                    cfg.duckduckgo(Some(&username))
+                        .and_then(|user| user.base_url.clone())
+                });
+                if request_otp {
+                    let client = ddep::Client::new(username, None, None, base_url);
+                    request_otp_mail(&client).await?;
+                    info!("Login email requested, log in with the passphrase in it using --otp");
+                    return Ok(());
+                }
+                let otp_source = match otp {
+                    Some(otp) => Otp::Given(otp),
+                    None if manual => Otp::Prompt,
+                    None => Otp::Mail(cfg.account(account.as_deref())?),
+                };
+                login_ddep(username, otp_source, &path, base_url).await?;
+            }
+            kind => {
+                let api_key = api_key
+                    .ok_or_else(|| format!("--api-key is required to log in to {}", kind))?;
+                let entry = providers::login_api_key(kind, &api_key, base_url).await?;
+                info!("Logged in as {}", entry.account);
+                ConfigFile::update(&path, |cfg| {
+                    cfg.upsert_api_key(kind, entry);
+                    Ok(())
+                })?;
+            }
+        },
+        Commands::Fetch {
+            config,
+            account,
+            limit,
+            since,
+            before,
+            newest_first,
+            all,
+            unseen,
+            format,
+        } => {
+            let cfg = ConfigFile::read(&config)?;
+            let config = cfg.account(account.as_deref())?;
+            let template = format.map(|f| cfg.template(&f)).transpose()?;
+            let query = email::FetchQuery {
+                // --unseen conflicts with --all, and is what fetch does without either
+                unseen: unseen || !all,
+                since,
+                before,
+            };
+            let options = email::StreamOptions {
+                newest_first,
+                limit,
+                ..Default::default()
+            };
+            let mut emails = pin!(config.stream(&query.to_imap(), options));
+            info!("Fetching emails...");
+            while let Some(email) = emails.next().await {
+                match &template {
+                    Some(template) => println!("{}", template.render(&email?)),
+                    None => println!("{}", email?.to_string().as_str().blue()),
+                }
+            }
+            info!("Emails fetched successfully!");
         }
-        Commands::Fetch { config } => {
-            let config: email::EmailConfig = email::EmailConfig::read(&config)?;
-            println!("{}", "Fetching emails...".cyan());
-            config.fetch_email("NOT SEEN")?;
-            println!("{}", "Emails fetched successfully!".green());
+        Commands::List {
+            config,
+            account,
+            limit,
+            since,
+            before,
+            newest_first,
+            unseen,
+            json,
+        } => {
+            let cfg = ConfigFile::read(&config)?;
+            let config = cfg.account(account.as_deref())?;
+            let query = email::FetchQuery {
+                unseen,
+                since,
+                before,
+            };
+            let options = email::ListOptions {
+                newest_first,
+                limit,
+            };
+            let summaries = config.list(&query.to_imap(), options).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&summaries)?);
+            } else {
+                print_summaries(&summaries);
+            }
         }
-        #[cfg(feature = "ddep")]
-        Commands::Address { config } => {
-            if let Ok(cfg) = ddep::DdConfig::read(&config) {
-                let client: ddep::Client = cfg.into();
-                let addr = client.generate_alias().await?;
-                println!("{}", "Generated success!\n".green());
-                println!("{}", format!("{}@duck.com", addr).red());
+        Commands::Show {
+            config,
+            account,
+            uid,
+            raw,
+            headers,
+            part,
+            format,
+            no_pager,
+        } => {
+            use std::io::Write;
+            let cfg = ConfigFile::read(&config)?;
+            let config = cfg.account(account.as_deref())?;
+            let template = format.map(|f| cfg.template(&f)).transpose()?;
+            let source = config.raw(uid).await?;
+            if raw {
+                io::stdout().write_all(&source)?;
+                return Ok(());
+            }
+            let mail = mailparse::parse_mail(&source)?;
+            if let Some(template) = template {
+                let mut email = email::Email::from(mail);
+                email.uid = Some(uid);
+                println!("{}", template.render(&email));
+            } else if let Some(n) = part {
+                let part = view::part(&mail, n)?;
+                if part.ctype.mimetype.starts_with("text/") {
+                    page(&part.get_body()?, no_pager)?;
+                } else {
+                    io::stdout().write_all(&part.get_body_raw()?)?;
+                }
+            } else if headers {
+                let dump: String = mail
+                    .headers
+                    .iter()
+                    .map(|h| format!("{}: {}\n", h.get_key().bold(), h.get_value()))
+                    .collect();
+                page(&dump, no_pager)?;
             } else {
-                println!(
-                    "{}",
-                    "Config of duckduckgo email protection not found!".red()
-                );
-                println!(
-                    "{}",
-                    "You can regist the services from https://duckduckgo.com/email/start".blue()
-                );
-                println!("{}", "Or set username with init command".blue());
-                #[cfg(not(feature = "gui"))]
-                {
-                    println!("{}", "Open browser and visit site? [0]".yellow());
-                    println!(
-                        "{}",
-                        "Setup username of duckduckgo email protection services? [1]".yellow()
-                    );
-                    let stdin = io::stdin();
-                    let input = {
-                        stdin
-                            .lock()
-                            .lines()
-                            .next()
-                            .unwrap_or_else(|| Ok(String::from("")))?
-                    };
-                    match input.trim().to_lowercase().as_str() {
-                        "0" => {
-                            mail_sitter::utils::browser::open(
-                                "https://duckduckgo.com/email/start",
-                            )?;
-                        }
-                        "1" => {
-                            println!("{}", "Your username: \n\n".cyan());
-                            let stdin = io::stdin();
-                            let input = { stdin.lock().lines().next() };
-                            if let Some(Ok(username)) = input {
-                                let username = username.trim();
-                                let email_cfg = email::EmailConfig::read(&config)?;
-                                login_ddep(username.to_string(), email_cfg).await?;
-                            }
-                        }
-                        _ => {
-                            println!("{}", "Exit.".yellow());
-                        }
-                    }
+                page(&view::render_message(&mail)?, no_pager)?;
+            }
+        }
+        Commands::Otp {
+            config,
+            account,
+            from,
+            wait,
+            interval,
+            kind,
+        } => {
+            let cfg = ConfigFile::read(&config)?;
+            let account = cfg.account(account.as_deref())?;
+            let code = otp::wait_for(
+                account,
+                &from,
+                &cfg.otp,
+                kind,
+                Duration::from_secs(wait),
+                interval,
+            )
+            .await?;
+            // only the code, for `$(ms otp ...)`
+            println!("{}", code.value);
+        }
+        Commands::Wait {
+            config,
+            account,
+            from,
+            subject,
+            body_regex,
+            since_now,
+            timeout,
+            interval,
+        } => {
+            let cfg = ConfigFile::read(&config).map_err(Exit::with(EXIT_CONFIG))?;
+            let account = cfg
+                .account(account.as_deref())
+                .map_err(Exit::with(EXIT_CONFIG))?;
+            let body = body_regex
+                .as_deref()
+                .map(regex::Regex::new)
+                .transpose()
+                .map_err(|e| Exit::with(EXIT_CRITERIA)(e.into()))?;
+            let mut filter = email::MailFilter {
+                from,
+                subject,
+                body,
+                ..Default::default()
+            };
+            filter.query().map_err(Exit::with(EXIT_CRITERIA))?;
+            let waited = async {
+                if since_now {
+                    filter.after = Some(account.watermark().await?);
+                    filter.since = Some(chrono::Utc::now());
                 }
+                account
+                    .wait_for(&filter, Duration::from_secs(timeout), interval)
+                    .await
+            };
+            let email = waited.await.map_err(|e| {
+                let code = if e.is::<email::TimeoutError>() {
+                    EXIT_TIMEOUT
+                } else {
+                    EXIT_MAILBOX
+                };
+                Exit::with(code)(e)
+            })?;
+            println!("{}", serde_json::to_string_pretty(&email)?);
+        }
+        Commands::Config { config, action } => parse_config_cmd(&config, action)?,
+        #[cfg(feature = "ddep")]
+        Commands::Ddg { config, action } => parse_ddg_cmd(&config, action).await?,
+        Commands::Aliases { registry, action } => parse_aliases_cmd(&registry, action).await?,
+        Commands::Address {
+            config,
+            provider,
+            account,
+            email_account,
+            registry,
+            label,
+            site,
+            note,
+        } => {
+            let file = ConfigFile::read_or_default(&config)?;
+            #[cfg(feature = "ddep")]
+            if provider == ProviderKind::Duckduckgo && file.duckduckgo(account.as_deref()).is_none()
+            {
+                return setup_ddep(&file, &config).await;
+            }
+            #[cfg(feature = "ddep")]
+            if provider == ProviderKind::Duckduckgo {
+                revalidate_ddep(&config, account.as_deref(), email_account.as_deref()).await?;
             }
+            let details = AliasDetails {
+                label,
+                site,
+                notes: note.into_iter().collect(),
+            };
+            let alias = generate_address(
+                &config,
+                provider,
+                account.as_deref(),
+                email_account.as_deref(),
+                &registry,
+                details,
+            )
+            .await?;
+            info!("Generated success!");
+            println!("{}", alias.address.red());
         }
+        Commands::Inbox { config, action } => parse_inbox_cmd(&config, action).await?,
     }
     Ok(())
 }
 
+/// Send diagnostics to stderr, at the level of `-v` and `-q` unless `RUST_LOG` is set.
+fn init_logging(args: &Args) {
+    use tracing_subscriber::EnvFilter;
+    let level = log_level(args.verbose, args.quiet);
+    // other crates only speak up on warnings, unless asked to be quieter
+    let others = if args.quiet > 0 { level } else { "warn" };
+    let mut filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(format!("{},mail_sitter={},ms={}", others, level, level))
+    });
+    if args.trace {
+        filter = filter.add_directive(
+            format!("{}=trace", mail_sitter::protocol::TARGET)
+                .parse()
+                .unwrap(),
+        );
+    }
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(args.color.enabled(io::stderr().is_terminal()))
+        .with_target(args.verbose > 0 || args.trace)
+        .without_time()
+        .init();
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    colored::control::set_override(true);
-
     #[cfg(feature = "gui")]
     {
         klask::run_derived::<Args, _>(klask::Settings::default(), |o| println!("{:#?}", o));
     }
 
     let args = Args::parse();
-    parse_cmd(args.command).await?;
-    Ok(())
+    colored::control::set_override(args.color.enabled(io::stdout().is_terminal()));
+    STDERR_COLOR.store(
+        args.color.enabled(io::stderr().is_terminal()),
+        Ordering::Relaxed,
+    );
+    init_logging(&args);
+    let result = parse_cmd(args.command).await;
+    // log out of the sessions kept for reuse
+    mailbox::Pool::global().close().await;
+    match result {
+        Err(e) => match e.downcast::<Exit>() {
+            Ok(exit) => {
+                error!("{}", exit.error);
+                std::process::exit(exit.code);
+            }
+            Err(e) => Err(e),
+        },
+        ok => ok,
+    }
 }
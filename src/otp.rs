@@ -0,0 +1,240 @@
+//! Verification codes, passphrases and magic links in emails
+use crate::config::ConfigError;
+use crate::email::{Email, EmailConfig, TimeoutError, WaitOptions};
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::future::ready;
+use std::time::Duration;
+
+/// Words announcing a code, the code follows within a few characters
+const CODE: &str = r"(?i)\b(?:(?:code|otp|pin|passcode|password|verification|token)\b[^\w\r\n]{0,3})+(?:is\b[^\w\r\n]{0,3})?\s*([A-Za-z0-9]{4,10}|\d{3}[ -]\d{3})\b";
+/// A line holding nothing but a 4 to 8 digit number
+const LONE_NUMBER: &str = r"(?m)^\s*(\d{4,8})\s*$";
+/// Words separated by spaces or dashes in its own paragraph after `passphrase`
+const PASSPHRASE: &str =
+    r"(?is)passphrase.*?\r?\n\s*\r?\n\s*([a-z]+(?:[ -][a-z]+){2,})\s*(?:\r?\n|$)";
+const LINK: &str = r#"https?://[^\s<>"'\)\]]+"#;
+/// Parts of a URL telling it is a verification or login link
+const LINK_HINTS: [&str; 10] = [
+    "verify",
+    "verification",
+    "confirm",
+    "activate",
+    "magic",
+    "login",
+    "signin",
+    "sign-in",
+    "token",
+    "otp",
+];
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeKind {
+    /// Digits only, e.g. `123456`
+    Numeric,
+    /// Letters and digits, e.g. `A1B2C3`
+    Alphanumeric,
+    /// Words, e.g. `unstamped matching onboard proofs`
+    Passphrase,
+    /// Verification or magic link
+    Link,
+}
+
+impl CodeKind {
+    fn of(value: &str) -> Self {
+        if value.starts_with("http://") || value.starts_with("https://") {
+            Self::Link
+        } else if value
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == ' ' || c == '-')
+        {
+            Self::Numeric
+        } else if value.contains(' ') || value.matches('-').count() > 1 {
+            Self::Passphrase
+        } else {
+            Self::Alphanumeric
+        }
+    }
+}
+
+impl std::str::FromStr for CodeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "numeric" => Ok(Self::Numeric),
+            "alphanumeric" => Ok(Self::Alphanumeric),
+            "passphrase" => Ok(Self::Passphrase),
+            "link" => Ok(Self::Link),
+            _ => Err(format!(
+                "unknown code kind `{}`, expected numeric, alphanumeric, passphrase or link",
+                s
+            )),
+        }
+    }
+}
+
+/// Code found in an email.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Code {
+    pub kind: CodeKind,
+    pub value: String,
+}
+
+/// Extraction rule for the mail of one sender, tried before the built-in ones.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OtpPattern {
+    /// Sender address, or domain matching its subdomains too, e.g. `github.com`
+    pub from: String,
+    /// Regex matched against the subject and body, the code is the first group or the whole match
+    pub pattern: String,
+    /// Kind of the code, guessed from the value when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<CodeKind>,
+}
+
+impl OtpPattern {
+    /// Require a sender usable in an IMAP search, and a pattern that compiles.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.from.is_empty() || self.from.contains('"') {
+            return Err(ConfigError::invalid(
+                "from",
+                format!("expected an address or domain, got `{}`", self.from),
+            ));
+        }
+        Regex::new(&self.pattern).map_err(|e| ConfigError::invalid("pattern", e.to_string()))?;
+        Ok(())
+    }
+
+    /// Whether the pattern applies to mail from `addr`.
+    pub fn applies_to(&self, addr: &str) -> bool {
+        sender_matches(&self.from, addr)
+    }
+
+    fn extract(&self, text: &str) -> Option<Code> {
+        let re = Regex::new(&self.pattern).ok()?;
+        let captures = re.captures(text)?;
+        let value = captures.get(1).or_else(|| captures.get(0))?.as_str().trim();
+        Some(Code {
+            kind: self.kind.unwrap_or_else(|| CodeKind::of(value)),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Whether `addr` is `sender`, or in the domain `sender`.
+pub fn sender_matches(sender: &str, addr: &str) -> bool {
+    let (sender, addr) = (sender.to_lowercase(), addr.to_lowercase());
+    if sender.contains('@') {
+        return addr == sender;
+    }
+    let domain = addr.rsplit('@').next().unwrap_or_default();
+    domain == sender || domain.ends_with(&format!(".{}", sender))
+}
+
+/// Find a code in `email`, trying the patterns of its sender first,
+/// then passphrases, codes and links. Only codes of `kind` are returned when given.
+pub fn extract(email: &Email, patterns: &[OtpPattern], kind: Option<CodeKind>) -> Option<Code> {
+    let text = format!("{}\r\n\r\n{}", email.subject, email.body);
+    let from = email.from_addr().unwrap_or_default();
+    let wanted = |code: &Code| kind.is_none_or(|k| k == code.kind);
+    patterns
+        .iter()
+        .filter(|p| p.applies_to(&from))
+        .filter_map(|p| p.extract(&text))
+        .find(|code| wanted(code))
+        .or_else(|| builtin(&text).into_iter().find(|code| wanted(code)))
+}
+
+/// Candidates of the built-in rules, in order of preference.
+fn builtin(text: &str) -> Vec<Code> {
+    let mut codes = vec![];
+    let captured = |re: &str| -> Vec<String> {
+        Regex::new(re)
+            .unwrap()
+            .captures_iter(text)
+            .filter_map(|c| c.get(1).map(|m| m.as_str().to_string()))
+            .collect()
+    };
+    for value in captured(PASSPHRASE) {
+        codes.push(Code {
+            kind: CodeKind::Passphrase,
+            value,
+        });
+    }
+    // a word following "code" is only a code when it holds a digit
+    for value in captured(CODE)
+        .into_iter()
+        .chain(captured(LONE_NUMBER))
+        .filter(|v| v.chars().any(|c| c.is_ascii_digit()))
+    {
+        codes.push(Code {
+            kind: CodeKind::of(&value),
+            value,
+        });
+    }
+    for link in Regex::new(LINK).unwrap().find_iter(text) {
+        let url = link.as_str().trim_end_matches(['.', ',', ';']);
+        let lower = url.to_lowercase();
+        if LINK_HINTS.iter().any(|hint| lower.contains(hint)) {
+            codes.push(Code {
+                kind: CodeKind::Link,
+                value: url.to_string(),
+            });
+        }
+    }
+    codes
+}
+
+#[derive(Debug)]
+pub struct NoCodeError(String);
+
+impl fmt::Display for NoCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No code from {} before the timeout", self.0)
+    }
+}
+
+impl Error for NoCodeError {}
+
+/// Poll the unread mail of `account` from `sender` until a code shows up, newest mail first.
+/// The mail the code is taken from is marked as read, so it is not used twice.
+pub async fn wait_for(
+    account: &EmailConfig,
+    sender: &str,
+    patterns: &[OtpPattern],
+    kind: Option<CodeKind>,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<Code, Box<dyn Error>> {
+    if sender.is_empty() || sender.contains('"') {
+        return Err(format!("Invalid sender `{}`", sender).into());
+    }
+    let query = format!("UNSEEN FROM \"{}\"", sender);
+    let options = WaitOptions {
+        timeout,
+        period: interval,
+        newest_first: true,
+        ..Default::default()
+    };
+    let has_code = |e: &Email| {
+        // IMAP FROM is a substring search, check the address
+        ready(
+            e.from_addr().is_some_and(|a| sender_matches(sender, &a))
+                && extract(e, patterns, kind).is_some(),
+        )
+    };
+    let email = match account.wait_until(&query, has_code, options).await {
+        Err(e) if e.is::<TimeoutError>() => return Err(Box::new(NoCodeError(sender.to_string()))),
+        result => result?,
+    };
+    if let Some(uid) = email.uid {
+        account.mark_seen(&[uid]).await?;
+    }
+    extract(&email, patterns, kind).ok_or_else(|| NoCodeError(sender.to_string()).into())
+}
@@ -0,0 +1,147 @@
+//! Trace of the IMAP and HTTP traffic, enabled at the trace level of [`TARGET`].
+//! Credentials are redacted.
+use std::io::{self, Read, Write};
+
+/// Target of protocol trace events
+pub const TARGET: &str = "mail_sitter::protocol";
+/// Replaces credentials in the trace
+pub const REDACTED: &str = "***";
+/// Parts of header and query parameter names carrying credentials
+const SECRETS: [&str; 8] = [
+    "authorization",
+    "authentication",
+    "cookie",
+    "api-key",
+    "api_key",
+    "apikey",
+    "token",
+    "otp",
+];
+
+/// Whether the protocol trace is wanted.
+pub fn enabled() -> bool {
+    tracing::enabled!(target: TARGET, tracing::Level::TRACE)
+}
+
+fn is_secret(name: &str) -> bool {
+    let name = name.to_lowercase();
+    SECRETS.iter().any(|secret| name.contains(secret))
+}
+
+/// `url` with the values of credential query parameters redacted.
+pub fn redact_url(url: &str) -> String {
+    let Some((base, query)) = url.split_once('?') else {
+        return url.to_string();
+    };
+    let query: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if is_secret(key) => format!("{}={}", key, REDACTED),
+            _ => pair.to_string(),
+        })
+        .collect();
+    format!("{}?{}", base, query.join("&"))
+}
+
+/// `value` of the header `name`, or [`REDACTED`] when it is a credential.
+pub fn redact_header<'a>(name: &str, value: &'a str) -> &'a str {
+    if is_secret(name) {
+        REDACTED
+    } else {
+        value
+    }
+}
+
+/// `line` of an IMAP command with the password of a LOGIN redacted.
+pub fn redact_imap(line: &str) -> String {
+    let mut words = line.splitn(4, ' ');
+    match (words.next(), words.next(), words.next(), words.next()) {
+        (Some(tag), Some(command), Some(user), Some(_))
+            if command.eq_ignore_ascii_case("LOGIN") =>
+        {
+            format!("{} {} {} {}", tag, command, user, REDACTED)
+        }
+        _ => line.to_string(),
+    }
+}
+
+/// Stream tracing the lines read from and written to it, used for the IMAP connection.
+pub struct Traced<S> {
+    inner: S,
+    read: Vec<u8>,
+    written: Vec<u8>,
+}
+
+impl<S> Traced<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            read: vec![],
+            written: vec![],
+        }
+    }
+}
+
+impl<S: Read> Read for Traced<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if enabled() {
+            trace_lines("<", &mut self.read, &buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Traced<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if enabled() {
+            trace_lines(">", &mut self.written, &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Trace the complete lines of `pending` after appending `data`, keeping the rest.
+fn trace_lines(direction: &str, pending: &mut Vec<u8>, data: &[u8]) {
+    pending.extend_from_slice(data);
+    while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+        let line: Vec<u8> = pending.drain(..=end).collect();
+        let line = String::from_utf8_lossy(&line);
+        tracing::trace!(target: TARGET, "{} {}", direction, redact_imap(line.trim_end()));
+    }
+}
+
+/// Send a request, tracing it and the response headers. Bodies are not traced.
+#[cfg(feature = "reqwest")]
+pub async fn send(request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    if !enabled() {
+        return request.send().await;
+    }
+    let (client, request) = request.build_split();
+    let request = request?;
+    tracing::trace!(target: TARGET, "> {} {}", request.method(), redact_url(request.url().as_str()));
+    trace_headers(">", request.headers());
+    let response = client.execute(request).await?;
+    tracing::trace!(target: TARGET, "< {}", response.status());
+    trace_headers("<", response.headers());
+    Ok(response)
+}
+
+#[cfg(feature = "reqwest")]
+fn trace_headers(direction: &str, headers: &reqwest::header::HeaderMap) {
+    for (name, value) in headers {
+        let value = value.to_str().unwrap_or("<binary>");
+        tracing::trace!(
+            target: TARGET,
+            "{} {}: {}",
+            direction,
+            name,
+            redact_header(name.as_str(), value)
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! addy.io, formerly AnonAddy, `https://addy.io`
+use super::{AliasProvider, ApiKeyClient, ApiKeyConfig, ProviderKind, ProviderStatus};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::error::Error;
+
+pub const API_BASE: &str = "https://app.addy.io";
+const ALIASES: &str = "/api/v1/aliases";
+const ACCOUNT_DETAILS: &str = "/api/v1/account-details";
+/// Shared domain used for new aliases
+const DEFAULT_DOMAIN: &str = "anonaddy.me";
+/// Aliases per page of `list`, the most the API gives
+const PAGE_SIZE: u32 = 100;
+
+#[derive(Deserialize, Debug)]
+struct Data<T> {
+    data: T,
+}
+
+/// A page of a paginated list
+#[derive(Deserialize, Debug)]
+struct Page<T> {
+    data: Vec<T>,
+    meta: Option<PageMeta>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PageMeta {
+    current_page: u64,
+    last_page: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct Alias {
+    email: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct AccountDetails {
+    username: String,
+    subscription: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Client {
+    api: ApiKeyClient,
+}
+
+impl From<&ApiKeyConfig> for Client {
+    fn from(cfg: &ApiKeyConfig) -> Self {
+        Self::new(
+            cfg.account.clone(),
+            Some(cfg.api_key.clone()),
+            cfg.base_url.clone(),
+        )
+    }
+}
+
+impl Client {
+    pub fn new(account: String, api_key: Option<String>, base_url: Option<String>) -> Client {
+        let auth = |api_key: &str| {
+            vec![
+                ("authorization", format!("Bearer {}", api_key)),
+                ("x-requested-with", "XMLHttpRequest".to_string()),
+            ]
+        };
+        Client {
+            api: ApiKeyClient::new("addy.io", API_BASE, auth, account, api_key, base_url),
+        }
+    }
+
+    /// Responses are wrapped in `{"data": ...}`
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, Box<dyn Error>> {
+        let data: Data<T> = self.api.get(path).await?;
+        Ok(data.data)
+    }
+}
+
+#[async_trait(?Send)]
+impl AliasProvider for Client {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::Addy
+    }
+
+    fn account(&self) -> String {
+        self.api.account.clone()
+    }
+
+    async fn login(&mut self, credential: &str) -> Result<(), Box<dyn Error>> {
+        self.api.api_key = Some(credential.to_string());
+        let details: AccountDetails = self.get(ACCOUNT_DETAILS).await?;
+        if self.api.account.is_empty() {
+            self.api.account = details.username;
+        }
+        Ok(())
+    }
+
+    async fn generate(&self) -> Result<String, Box<dyn Error>> {
+        let alias: Data<Alias> = self
+            .api
+            .post(ALIASES, serde_json::json!({ "domain": DEFAULT_DOMAIN }))
+            .await?;
+        Ok(alias.data.email)
+    }
+
+    async fn list(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut aliases = vec![];
+        for page in 1.. {
+            let response: Page<Alias> = self
+                .api
+                .get(&format!(
+                    "{}?page[number]={}&page[size]={}",
+                    ALIASES, page, PAGE_SIZE
+                ))
+                .await?;
+            aliases.extend(response.data.into_iter().map(|a| a.email));
+            if response.meta.is_none_or(|m| m.current_page >= m.last_page) {
+                break;
+            }
+        }
+        Ok(aliases)
+    }
+
+    async fn status(&self) -> Result<ProviderStatus, Box<dyn Error>> {
+        let details: AccountDetails = self.get(ACCOUNT_DETAILS).await?;
+        let aliases = self.list().await?;
+        Ok(ProviderStatus {
+            provider: self.kind(),
+            account: details.username,
+            forwarding: None,
+            aliases: Some(aliases.len() as u64),
+            premium: details.subscription.map(|s| s != "free"),
+        })
+    }
+}
@@ -0,0 +1,316 @@
+//! Module for alias providers
+//!
+//! Every provider implements [`AliasProvider`], duckduckgo email protection
+//! is built with the `ddep` feature and the others with their own feature.
+
+#[cfg(feature = "addy")]
+pub mod addy;
+#[cfg(feature = "relay")]
+pub mod relay;
+#[cfg(feature = "simplelogin")]
+pub mod simplelogin;
+
+use crate::aliases::{AliasDetails, AliasRecord, Registry};
+use crate::config::{ConfigError, ConfigFile};
+use crate::Config;
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// A service handing out forwarding aliases
+#[async_trait(?Send)]
+pub trait AliasProvider {
+    /// Kind of the provider
+    fn kind(&self) -> ProviderKind;
+
+    /// Account aliases are generated for
+    fn account(&self) -> String;
+
+    /// Log in with a one time passphrase or an API key, depending on the provider
+    async fn login(&mut self, credential: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Generate a new alias and return its full address
+    async fn generate(&self) -> Result<String, Box<dyn Error>>;
+
+    /// Generate a new alias and record it with `details` in the alias registry at `registry`.
+    async fn generate_recorded(
+        &self,
+        registry: &str,
+        details: AliasDetails,
+    ) -> Result<AliasRecord, Box<dyn Error>> {
+        let address = self.generate().await?;
+        let mut alias = AliasRecord::new(address, self.kind().name(), &self.account());
+        alias.label = details.label;
+        alias.site = details.site;
+        alias.notes = details.notes;
+        Registry::update(registry, |r| {
+            r.record(alias.clone());
+            Ok(())
+        })?;
+        Ok(alias)
+    }
+
+    /// Full addresses of the aliases of the account, when the provider lists them
+    async fn list(&self) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Account details
+    async fn status(&self) -> Result<ProviderStatus, Box<dyn Error>>;
+}
+
+/// Account details reported by a provider
+#[derive(Serialize, Debug, Clone)]
+pub struct ProviderStatus {
+    pub provider: ProviderKind,
+    pub account: String,
+    /// Real address mail is forwarded to
+    pub forwarding: Option<String>,
+    /// Number of aliases of the account
+    pub aliases: Option<u64>,
+    pub premium: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    Duckduckgo,
+    Simplelogin,
+    Addy,
+    Relay,
+}
+
+impl ProviderKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Duckduckgo => "duckduckgo",
+            Self::Simplelogin => "simplelogin",
+            Self::Addy => "addy",
+            Self::Relay => "relay",
+        }
+    }
+
+    /// Cargo feature the provider is built with
+    pub fn feature(&self) -> &'static str {
+        match self {
+            Self::Duckduckgo => "ddep",
+            kind => kind.name(),
+        }
+    }
+}
+
+impl fmt::Display for ProviderKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for ProviderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "duckduckgo" | "ddg" => Ok(Self::Duckduckgo),
+            "simplelogin" => Ok(Self::Simplelogin),
+            "addy" | "anonaddy" => Ok(Self::Addy),
+            "relay" | "firefox" => Ok(Self::Relay),
+            _ => Err(format!(
+                "Unknown provider `{}`, expected one of duckduckgo, simplelogin, addy, relay",
+                s
+            )),
+        }
+    }
+}
+
+/// Settings of a provider logging in with an API key
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Name of the account, used to pick one when there are several
+    pub account: String,
+    pub api_key: String,
+    /// API base URL, defaults to the public service
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+}
+
+impl ApiKeyConfig {
+    /// Reject an empty or blank API key.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.api_key.trim().is_empty() {
+            return Err(ConfigError::invalid("api_key", "API key is empty"));
+        }
+        Ok(())
+    }
+}
+
+/// Entry with the given account, or the first one when `account` is `None`.
+pub fn select<'a>(entries: &'a [ApiKeyConfig], account: Option<&str>) -> Option<&'a ApiKeyConfig> {
+    match account {
+        Some(account) => entries.iter().find(|e| e.account == account),
+        None => entries.first(),
+    }
+}
+
+/// Build the provider of `kind` from its settings in the config file.
+/// `account` selects the duckduckgo username or API key account.
+pub fn from_config(
+    kind: ProviderKind,
+    cfg: &ConfigFile,
+    account: Option<&str>,
+) -> Result<Box<dyn AliasProvider>, Box<dyn Error>> {
+    let not_configured =
+        || -> Box<dyn Error> { format!("Config of {} not found, please login first", kind).into() };
+    match kind {
+        #[cfg(feature = "ddep")]
+        ProviderKind::Duckduckgo => {
+            let user = cfg.duckduckgo(account).ok_or_else(not_configured)?;
+            let client: crate::ddep::Client = user.clone().into();
+            Ok(Box::new(client))
+        }
+        #[cfg(feature = "simplelogin")]
+        ProviderKind::Simplelogin => {
+            let entry = select(&cfg.providers.simplelogin, account).ok_or_else(not_configured)?;
+            Ok(Box::new(simplelogin::Client::from(entry)))
+        }
+        #[cfg(feature = "addy")]
+        ProviderKind::Addy => {
+            let entry = select(&cfg.providers.addy, account).ok_or_else(not_configured)?;
+            Ok(Box::new(addy::Client::from(entry)))
+        }
+        #[cfg(feature = "relay")]
+        ProviderKind::Relay => {
+            let entry = select(&cfg.providers.relay, account).ok_or_else(not_configured)?;
+            Ok(Box::new(relay::Client::from(entry)))
+        }
+        #[allow(unreachable_patterns)]
+        kind => Err(format!(
+            "mail_sitter was built without {}, rebuild with --features {}",
+            kind,
+            kind.feature()
+        )
+        .into()),
+    }
+}
+
+/// HTTP client of a provider logging in with an API key. simplelogin, addy and relay
+/// wrap it with their endpoints and types.
+#[cfg(any(feature = "simplelogin", feature = "addy", feature = "relay"))]
+#[derive(Debug)]
+pub struct ApiKeyClient {
+    pub account: String,
+    pub api_key: Option<String>,
+    base_url: String,
+    session: reqwest::Client,
+    /// Name of the provider in errors
+    service: &'static str,
+    /// Headers carrying the API key
+    auth: fn(&str) -> Vec<(&'static str, String)>,
+}
+
+#[cfg(any(feature = "simplelogin", feature = "addy", feature = "relay"))]
+impl ApiKeyClient {
+    /// Client of `service`, at `base_url` or else `api_base`.
+    pub fn new(
+        service: &'static str,
+        api_base: &str,
+        auth: fn(&str) -> Vec<(&'static str, String)>,
+        account: String,
+        api_key: Option<String>,
+        base_url: Option<String>,
+    ) -> Self {
+        Self {
+            account,
+            api_key,
+            base_url: base_url.unwrap_or_else(|| api_base.to_string()),
+            session: reqwest::Client::new(),
+            service,
+            auth,
+        }
+    }
+
+    /// GET `path` and read the JSON response.
+    pub async fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+    ) -> Result<T, Box<dyn Error>> {
+        let url = format!("{}{}", self.base_url, path);
+        self.send(self.session.get(&url)).await
+    }
+
+    /// POST `body` as JSON to `path` and read the JSON response.
+    pub async fn post<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+        body: serde_json::Value,
+    ) -> Result<T, Box<dyn Error>> {
+        let url = format!("{}{}", self.base_url, path);
+        self.send(self.session.post(&url).json(&body)).await
+    }
+
+    async fn send<T: serde::de::DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<T, Box<dyn Error>> {
+        let api_key = self
+            .api_key
+            .as_deref()
+            .ok_or_else(|| format!("{} API key not set", self.service))?;
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in (self.auth)(api_key) {
+            headers.insert(name, reqwest::header::HeaderValue::from_str(&value)?);
+        }
+        let response = crate::protocol::send(request.headers(headers)).await?;
+        response.error_for_status_ref()?;
+        Ok(response.json().await?)
+    }
+}
+
+/// Client of a provider logging in with an API key, not logged in yet.
+#[cfg_attr(
+    not(any(feature = "simplelogin", feature = "addy", feature = "relay")),
+    allow(unused_variables)
+)]
+fn api_key_client(
+    kind: ProviderKind,
+    base_url: Option<String>,
+) -> Result<Box<dyn AliasProvider>, Box<dyn Error>> {
+    match kind {
+        #[cfg(feature = "simplelogin")]
+        ProviderKind::Simplelogin => Ok(Box::new(simplelogin::Client::new(
+            String::new(),
+            None,
+            base_url,
+        ))),
+        #[cfg(feature = "addy")]
+        ProviderKind::Addy => Ok(Box::new(addy::Client::new(String::new(), None, base_url))),
+        #[cfg(feature = "relay")]
+        ProviderKind::Relay => Ok(Box::new(relay::Client::new(String::new(), None, base_url))),
+        ProviderKind::Duckduckgo => {
+            Err("duckduckgo logs in with a one time passphrase, not an API key".into())
+        }
+        #[allow(unreachable_patterns)]
+        kind => Err(format!(
+            "mail_sitter was built without {}, rebuild with --features {}",
+            kind,
+            kind.feature()
+        )
+        .into()),
+    }
+}
+
+/// Log in to a provider with an API key, returning the settings to save.
+pub async fn login_api_key(
+    kind: ProviderKind,
+    api_key: &str,
+    base_url: Option<String>,
+) -> Result<ApiKeyConfig, Box<dyn Error>> {
+    let mut client = api_key_client(kind, base_url.clone())?;
+    client.login(api_key).await?;
+    Ok(ApiKeyConfig {
+        account: client.account(),
+        api_key: api_key.to_string(),
+        base_url,
+    })
+}
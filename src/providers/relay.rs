@@ -0,0 +1,103 @@
+//! Firefox Relay, `https://relay.firefox.com`
+use super::{AliasProvider, ApiKeyClient, ApiKeyConfig, ProviderKind, ProviderStatus};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::error::Error;
+
+pub const API_BASE: &str = "https://relay.firefox.com";
+const RELAY_ADDRESSES: &str = "/api/v1/relayaddresses/";
+const PROFILES: &str = "/api/v1/profiles/";
+const USERS: &str = "/api/v1/users/";
+
+#[derive(Deserialize, Debug)]
+struct RelayAddress {
+    full_address: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Profile {
+    has_premium: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct User {
+    email: String,
+}
+
+#[derive(Debug)]
+pub struct Client {
+    api: ApiKeyClient,
+}
+
+impl From<&ApiKeyConfig> for Client {
+    fn from(cfg: &ApiKeyConfig) -> Self {
+        Self::new(
+            cfg.account.clone(),
+            Some(cfg.api_key.clone()),
+            cfg.base_url.clone(),
+        )
+    }
+}
+
+impl Client {
+    pub fn new(account: String, api_key: Option<String>, base_url: Option<String>) -> Client {
+        let auth = |api_key: &str| vec![("authorization", format!("Token {}", api_key))];
+        Client {
+            api: ApiKeyClient::new("Firefox Relay", API_BASE, auth, account, api_key, base_url),
+        }
+    }
+
+    async fn user(&self) -> Result<User, Box<dyn Error>> {
+        let users: Vec<User> = self.api.get(USERS).await?;
+        Ok(users
+            .into_iter()
+            .next()
+            .ok_or("Firefox Relay returned no user")?)
+    }
+}
+
+#[async_trait(?Send)]
+impl AliasProvider for Client {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::Relay
+    }
+
+    fn account(&self) -> String {
+        self.api.account.clone()
+    }
+
+    async fn login(&mut self, credential: &str) -> Result<(), Box<dyn Error>> {
+        self.api.api_key = Some(credential.to_string());
+        let user = self.user().await?;
+        if self.api.account.is_empty() {
+            self.api.account = user.email;
+        }
+        Ok(())
+    }
+
+    async fn generate(&self) -> Result<String, Box<dyn Error>> {
+        let alias: RelayAddress = self
+            .api
+            .post(RELAY_ADDRESSES, serde_json::json!({ "enabled": true }))
+            .await?;
+        Ok(alias.full_address)
+    }
+
+    async fn list(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let aliases: Vec<RelayAddress> = self.api.get(RELAY_ADDRESSES).await?;
+        Ok(aliases.into_iter().map(|a| a.full_address).collect())
+    }
+
+    async fn status(&self) -> Result<ProviderStatus, Box<dyn Error>> {
+        let user = self.user().await?;
+        let profiles: Vec<Profile> = self.api.get(PROFILES).await?;
+        let aliases = self.list().await?;
+        Ok(ProviderStatus {
+            provider: self.kind(),
+            account: self.account(),
+            forwarding: Some(user.email),
+            aliases: Some(aliases.len() as u64),
+            premium: profiles.first().map(|p| p.has_premium),
+        })
+    }
+}
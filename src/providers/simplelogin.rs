@@ -0,0 +1,108 @@
+//! SimpleLogin, `https://simplelogin.io`
+use super::{AliasProvider, ApiKeyClient, ApiKeyConfig, ProviderKind, ProviderStatus};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::error::Error;
+
+pub const API_BASE: &str = "https://app.simplelogin.io";
+const RANDOM_ALIAS: &str = "/api/alias/random/new";
+const ALIASES: &str = "/api/v2/aliases";
+const USER_INFO: &str = "/api/user_info";
+const STATS: &str = "/api/stats";
+
+#[derive(Deserialize, Debug)]
+struct AliasResponse {
+    email: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct AliasesResponse {
+    aliases: Vec<AliasResponse>,
+}
+
+#[derive(Deserialize, Debug)]
+struct UserInfo {
+    email: String,
+    is_premium: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct Stats {
+    nb_alias: u64,
+}
+
+#[derive(Debug)]
+pub struct Client {
+    api: ApiKeyClient,
+}
+
+impl From<&ApiKeyConfig> for Client {
+    fn from(cfg: &ApiKeyConfig) -> Self {
+        Self::new(
+            cfg.account.clone(),
+            Some(cfg.api_key.clone()),
+            cfg.base_url.clone(),
+        )
+    }
+}
+
+impl Client {
+    pub fn new(account: String, api_key: Option<String>, base_url: Option<String>) -> Client {
+        let auth = |api_key: &str| vec![("authentication", api_key.to_string())];
+        Client {
+            api: ApiKeyClient::new("SimpleLogin", API_BASE, auth, account, api_key, base_url),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl AliasProvider for Client {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::Simplelogin
+    }
+
+    fn account(&self) -> String {
+        self.api.account.clone()
+    }
+
+    async fn login(&mut self, credential: &str) -> Result<(), Box<dyn Error>> {
+        self.api.api_key = Some(credential.to_string());
+        let user: UserInfo = self.api.get(USER_INFO).await?;
+        if self.api.account.is_empty() {
+            self.api.account = user.email;
+        }
+        Ok(())
+    }
+
+    async fn generate(&self) -> Result<String, Box<dyn Error>> {
+        let alias: AliasResponse = self.api.post(RANDOM_ALIAS, serde_json::json!({})).await?;
+        Ok(alias.email)
+    }
+
+    async fn list(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut aliases = vec![];
+        for page in 0.. {
+            let response: AliasesResponse = self
+                .api
+                .get(&format!("{}?page_id={}", ALIASES, page))
+                .await?;
+            if response.aliases.is_empty() {
+                break;
+            }
+            aliases.extend(response.aliases.into_iter().map(|a| a.email));
+        }
+        Ok(aliases)
+    }
+
+    async fn status(&self) -> Result<ProviderStatus, Box<dyn Error>> {
+        let user: UserInfo = self.api.get(USER_INFO).await?;
+        let stats: Stats = self.api.get(STATS).await?;
+        Ok(ProviderStatus {
+            provider: self.kind(),
+            account: self.account(),
+            forwarding: Some(user.email),
+            aliases: Some(stats.nb_alias),
+            premium: Some(user.is_premium),
+        })
+    }
+}
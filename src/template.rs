@@ -0,0 +1,205 @@
+//! Output templates for emails, e.g. `{date} {from.name} {subject}`
+use crate::email::Email;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Utc};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Template of `Display for Email` and `fetch` without `--format`
+pub const DEFAULT: &str = "From: {from}\nSubject: {subject}\n\n{body}\n";
+/// Format of `{date}` and `{received}` without a spec, dates are in UTC
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M";
+/// Fields a template can use, for error messages
+const FIELDS: &str =
+    "uid, date, received, from, from.name, from.addr, to, subject, body, headers, header.<name>";
+
+#[derive(Debug)]
+pub struct TemplateError(String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for TemplateError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Uid,
+    Date,
+    Received,
+    From,
+    FromName,
+    FromAddr,
+    To,
+    Subject,
+    Body,
+    Headers,
+    /// Values of one header, joined by `, `
+    Header(String),
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Self, TemplateError> {
+        Ok(match name {
+            "uid" => Self::Uid,
+            "date" => Self::Date,
+            "received" => Self::Received,
+            "from" => Self::From,
+            "from.name" => Self::FromName,
+            "from.addr" => Self::FromAddr,
+            "to" => Self::To,
+            "subject" => Self::Subject,
+            "body" => Self::Body,
+            "headers" => Self::Headers,
+            _ => match name.strip_prefix("header.") {
+                Some(header) if !header.is_empty() => Self::Header(header.to_string()),
+                _ => {
+                    return Err(TemplateError(format!(
+                        "unknown field `{}`, expected one of {}",
+                        name, FIELDS
+                    )))
+                }
+            },
+        })
+    }
+
+    fn is_date(&self) -> bool {
+        matches!(self, Self::Date | Self::Received)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    /// `{field}` or `{field:spec}`, the spec is a strftime format for dates
+    /// and a maximum width in characters otherwise
+    Field(Field, Option<String>),
+}
+
+/// Parsed output template. `{field}` is replaced by a field of the email,
+/// `{{` and `}}` are literal braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = vec![];
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => {
+                                return Err(TemplateError(format!("unclosed `{{{}`", placeholder)))
+                            }
+                        }
+                    }
+                    let (name, spec) = match placeholder.split_once(':') {
+                        Some((name, spec)) => (name, Some(spec.to_string())),
+                        None => (placeholder.as_str(), None),
+                    };
+                    let field = Field::parse(name.trim())?;
+                    if let Some(spec) = &spec {
+                        check_spec(&field, spec)?;
+                    }
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Field(field, spec));
+                }
+                '}' => {
+                    return Err(TemplateError(
+                        "unmatched `}`, write `}}` for a literal brace".to_string(),
+                    ))
+                }
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        Ok(Self { segments })
+    }
+}
+
+fn check_spec(field: &Field, spec: &str) -> Result<(), TemplateError> {
+    if field.is_date() {
+        if StrftimeItems::new(spec).any(|item| matches!(item, Item::Error)) {
+            return Err(TemplateError(format!("invalid date format `{}`", spec)));
+        }
+    } else if spec.parse::<usize>().is_err() {
+        return Err(TemplateError(format!(
+            "invalid width `{}`, expected a number of characters",
+            spec
+        )));
+    }
+    Ok(())
+}
+
+impl Template {
+    /// Fill the template with the fields of `email`, missing fields are empty.
+    pub fn render(&self, email: &Email) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.clone(),
+                Segment::Field(field, spec) => field_value(email, field, spec.as_deref()),
+            })
+            .collect()
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        DEFAULT.parse().unwrap()
+    }
+}
+
+fn field_value(email: &Email, field: &Field, spec: Option<&str>) -> String {
+    // UTC, so the output does not depend on the machine
+    let date = |at: Option<DateTime<Utc>>| {
+        at.map(|at| at.format(spec.unwrap_or(DATE_FORMAT)).to_string())
+            .unwrap_or_default()
+    };
+    let value = match field {
+        Field::Date => return date(email.date),
+        Field::Received => return date(email.received),
+        Field::Uid => email.uid.map(|uid| uid.to_string()).unwrap_or_default(),
+        Field::From => email.from.clone(),
+        Field::FromName => email.from_name().unwrap_or_default(),
+        Field::FromAddr => email.from_addr().unwrap_or_default(),
+        Field::To => email.to.join(", "),
+        Field::Subject => email.subject.clone(),
+        Field::Body => email.body.clone(),
+        Field::Headers => email
+            .headers
+            .iter()
+            .map(|(k, v)| format!("{}: {}\n", k, v))
+            .collect(),
+        Field::Header(name) => email.header(name).collect::<Vec<_>>().join(", "),
+    };
+    match spec.and_then(|s| s.parse::<usize>().ok()) {
+        Some(width) if value.chars().count() > width => value.chars().take(width).collect(),
+        _ => value,
+    }
+}
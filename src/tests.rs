@@ -11,3 +11,1127 @@ mod test_ggep {
         )
     }
 }
+
+mod test_config_keys {
+    use crate::config::{create_edit_buffer, get_key, parse_value, set_key, unset_key};
+
+    #[test]
+    fn test_dotted_keys() {
+        let mut doc = parse_value("{email: a@b.c, providers: {duckduckgo: {token: t}}}");
+        set_key(&mut doc, "providers.duckduckgo.username", parse_value("me")).unwrap();
+        assert_eq!(
+            get_key(&doc, "providers.duckduckgo.username"),
+            Some(&parse_value("me"))
+        );
+        assert!(unset_key(&mut doc, "providers.duckduckgo.token").is_some());
+        assert!(get_key(&doc, "providers.duckduckgo.token").is_none());
+        assert!(unset_key(&mut doc, "providers.missing").is_none());
+    }
+
+    #[test]
+    fn test_indexes_create_sequences() {
+        let mut doc = parse_value("{}");
+        set_key(
+            &mut doc,
+            "providers.duckduckgo.0.username",
+            parse_value("me"),
+        )
+        .unwrap();
+        assert_eq!(
+            doc,
+            parse_value("{providers: {duckduckgo: [{username: me}]}}")
+        );
+        set_key(
+            &mut doc,
+            "providers.duckduckgo.1.username",
+            parse_value("you"),
+        )
+        .unwrap();
+        set_key(&mut doc, "providers.duckduckgo.0.token", parse_value("t")).unwrap();
+        assert_eq!(
+            doc,
+            parse_value("{providers: {duckduckgo: [{username: me, token: t}, {username: you}]}}")
+        );
+        assert!(set_key(
+            &mut doc,
+            "providers.duckduckgo.3.username",
+            parse_value("x")
+        )
+        .is_err());
+        assert!(set_key(&mut doc, "providers..username", parse_value("x")).is_err());
+        assert!(set_key(
+            &mut doc,
+            "providers.duckduckgo.0.username.x",
+            parse_value("x")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_edit_buffer_is_private_and_never_reused() {
+        let dir = std::env::temp_dir().join(format!("mailsitter-edit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config").to_string_lossy().to_string();
+        let buffer = create_edit_buffer(&path, b"a: 1\n").unwrap();
+        assert_eq!(buffer, dir.join("config.edit"));
+        assert_eq!(std::fs::read_to_string(&buffer).unwrap(), "a: 1\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&buffer).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // a file planted at the buffer path is not written through
+        assert!(create_edit_buffer(&path, b"pwd: secret\n").is_err());
+        assert_eq!(std::fs::read_to_string(&buffer).unwrap(), "a: 1\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+mod test_config {
+    use crate::config::{write_atomic, ConfigFile};
+    use crate::email::EmailConfig;
+    use crate::Config;
+    use std::path::Path;
+
+    #[test]
+    fn test_atomic_write_keeps_backup() {
+        let dir = std::env::temp_dir().join(format!("mailsitter-test-{}", std::process::id()));
+        let path = dir.join("config");
+        write_atomic(&path, b"a: 1\n").unwrap();
+        write_atomic(&path, b"a: 2\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a: 2\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("config.bak")).unwrap(),
+            "a: 1\n"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_migrates_the_existing_file() {
+        let dir = std::env::temp_dir().join(format!("mailsitter-save-{}", std::process::id()));
+        let path = dir.join("config");
+        let path = path.to_str().unwrap();
+        write_atomic(Path::new(path), b"username: me\ntoken: t\n").unwrap();
+        let cfg = ConfigFile {
+            accounts: vec![EmailConfig::new(
+                "a@b.c".to_string(),
+                "secret".to_string(),
+                "imap.b.c:993".to_string(),
+            )],
+            ..Default::default()
+        };
+        cfg.save(path).unwrap();
+        let saved = ConfigFile::read(path).unwrap();
+        assert_eq!(saved.version, ConfigFile::VERSION);
+        assert_eq!(saved.account(None).unwrap().email, "a@b.c");
+        assert_eq!(saved.providers.duckduckgo.len(), 1);
+        // nothing is written when the result would not load
+        let invalid = ConfigFile {
+            templates: [("bad".to_string(), "{unclosed".to_string())].into(),
+            ..Default::default()
+        };
+        assert!(invalid.save(path).is_err());
+        assert!(ConfigFile::read(path).unwrap().templates.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+mod test_config_schema {
+    use crate::config::{parse_value, version_of, ConfigFile};
+    use crate::Config;
+
+    #[test]
+    fn test_flat_config_is_migrated_into_sections() {
+        let doc = parse_value(
+            "{email: a@b.c, pwd: secret, smtp: 'imap.b.c:993', username: me, token: t, access_token: at}",
+        );
+        assert_eq!(version_of(&doc).unwrap(), 0);
+        let mut migrated = doc.clone();
+        ConfigFile::migrate(&mut migrated).unwrap();
+        assert_eq!(version_of(&migrated).unwrap(), ConfigFile::VERSION);
+        let cfg = ConfigFile::from_value(doc).unwrap();
+        assert_eq!(cfg.account(None).unwrap().email, "a@b.c");
+        #[cfg(feature = "ddep")]
+        assert_eq!(cfg.duckduckgo(None).unwrap().username, "me");
+    }
+
+    #[test]
+    fn test_invalid_smtp_is_rejected_at_load() {
+        let doc =
+            parse_value("{version: 2, accounts: [{email: a@b.c, pwd: secret, smtp: imap.b.c}]}");
+        let err = ConfigFile::from_value(doc).unwrap_err().to_string();
+        assert!(err.contains("accounts[0].smtp"), "{}", err);
+    }
+
+    #[test]
+    fn test_every_version_has_a_migration() {
+        use crate::aliases::Registry;
+        assert_eq!(ConfigFile::MIGRATIONS.len(), ConfigFile::VERSION as usize);
+        assert_eq!(Registry::MIGRATIONS.len(), Registry::VERSION as usize);
+    }
+
+    #[test]
+    fn test_newer_version_is_rejected() {
+        let doc = parse_value("{version: 999}");
+        assert!(ConfigFile::from_value(doc).is_err());
+    }
+}
+
+mod test_aliases {
+    use crate::aliases::{AliasRecord, Registry};
+
+    #[test]
+    fn test_search_and_annotate() {
+        let mut registry = Registry::default();
+        let mut alias = AliasRecord::new("abc@duck.com".to_string(), "duckduckgo", "me");
+        alias.site = Some("shop.example".to_string());
+        registry.record(alias);
+        registry.record(AliasRecord::new(
+            "xyz@duck.com".to_string(),
+            "duckduckgo",
+            "me",
+        ));
+
+        assert_eq!(registry.search("SHOP").count(), 1);
+        registry
+            .annotate(
+                "xyz",
+                Some("newsletter".to_string()),
+                None,
+                Some("sent once".to_string()),
+            )
+            .unwrap();
+        let found: Vec<_> = registry.search("sent once").collect();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].address, "xyz@duck.com");
+        assert_eq!(found[0].label.as_deref(), Some("newsletter"));
+        assert!(registry.annotate("nope", None, None, None).is_err());
+    }
+}
+
+mod test_alias_audit {
+    use crate::aliases::{AliasRecord, LeakPolicy, Registry};
+    use crate::email::{Email, Watermark};
+
+    fn mail(from: &str, to: &str) -> Email {
+        Email {
+            from: from.to_string(),
+            to: vec![to.to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_unexpected_sender_flags_leak() {
+        let mut registry = Registry::default();
+        let mut alias = AliasRecord::new("abc@duck.com".to_string(), "duckduckgo", "me");
+        alias.site = Some("https://shop.example.com/signup".to_string());
+        registry.record(alias);
+        let policy = LeakPolicy::default();
+
+        registry.observe(&mail("news@mail.example.com", "abc@duck.com"));
+        assert!(registry.flag_leaks(&policy).is_empty());
+
+        let seen = registry.observe(&mail("promo@spammer.net", "abc@duck.com"));
+        assert_eq!(seen, vec!["abc@duck.com".to_string()]);
+        let flagged = registry.flag_leaks(&policy);
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged[0].stats.messages, 2);
+    }
+
+    #[test]
+    fn test_public_suffixes_are_not_one_site() {
+        let mut registry = Registry::default();
+        let mut alias = AliasRecord::new("abc@duck.com".to_string(), "duckduckgo", "me");
+        alias.site = Some("https://www.shop.co.uk".to_string());
+        registry.record(alias);
+        registry.observe(&mail("news@mail.shop.co.uk", "abc@duck.com"));
+        registry.observe(&mail("promo@spammer.co.uk", "abc@duck.com"));
+        let alias = registry.get("abc@duck.com").unwrap();
+        assert_eq!(alias.expected_domains(), vec!["shop.co.uk".to_string()]);
+        assert_eq!(
+            alias.unexpected_domains(),
+            vec!["spammer.co.uk".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_audit_counts_each_message_once() {
+        let mut registry = Registry::default();
+        let mut first = mail("news@shop.example", "abc@duck.com");
+        first.uid = Some(7);
+        let mut second = mail("news@shop.example", "abc@duck.com");
+        second.uid = Some(8);
+        let emails = [first, second];
+        let upto = |uid| Watermark {
+            uid_validity: 1,
+            uid,
+        };
+
+        assert_eq!(registry.audit("me@example.com", upto(8), &emails), 2);
+        // an audit which started from the same watermark finishes later
+        assert_eq!(registry.audit("me@example.com", upto(7), &emails[..1]), 0);
+        assert_eq!(registry.audited["me@example.com"], upto(8));
+        assert_eq!(registry.get("abc@duck.com").unwrap().stats.messages, 2);
+    }
+
+    #[test]
+    fn test_rewritten_sender_names_alias() {
+        let mut registry = Registry::default();
+        let seen = registry.observe(&mail(
+            "Shop <hello_at_shop.example_quiet-blue-fox@duck.com>",
+            "me@example.org",
+        ));
+        assert_eq!(seen, vec!["quiet-blue-fox@duck.com".to_string()]);
+        let alias = registry.get("quiet-blue-fox").unwrap();
+        assert!(alias.stats.senders.contains_key("hello@shop.example"));
+    }
+}
+
+mod test_otp {
+    use crate::email::Email;
+    use crate::otp::{extract, sender_matches, CodeKind, OtpPattern};
+
+    fn mail(from: &str, subject: &str, body: &str) -> Email {
+        Email {
+            from: from.to_string(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_builtin_codes() {
+        let numeric = mail("a@b.c", "Sign in", "Your verification code is: 482913.\r\n");
+        let code = extract(&numeric, &[], None).unwrap();
+        assert_eq!(
+            (code.kind, code.value.as_str()),
+            (CodeKind::Numeric, "482913")
+        );
+
+        let alnum = mail("a@b.c", "Your code: X7K2QP", "Use the code below.");
+        let code = extract(&alnum, &[], None).unwrap();
+        assert_eq!(
+            (code.kind, code.value.as_str()),
+            (CodeKind::Alphanumeric, "X7K2QP")
+        );
+
+        let duck = "Or, enter this one-time passphrase in your open DuckDuckGo tab:\r\n\r\nunstamped matching onboard proofs\r\n\r\n";
+        let code = extract(&mail("support@duck.com", "", duck), &[], None).unwrap();
+        assert_eq!(code.value, "unstamped matching onboard proofs");
+        assert_eq!(code.kind, CodeKind::Passphrase);
+
+        let link = "Confirm your address: https://app.example.com/verify?token=abc.\r\nhttps://example.com/help";
+        let code = extract(&mail("a@b.c", "Welcome", link), &[], None).unwrap();
+        assert_eq!(code.value, "https://app.example.com/verify?token=abc");
+        assert!(extract(
+            &mail("a@b.c", "Welcome", link),
+            &[],
+            Some(CodeKind::Numeric)
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_sender_pattern_wins() {
+        let patterns = vec![OtpPattern {
+            from: "example.com".to_string(),
+            pattern: r"ref ([A-Z]{6})".to_string(),
+            kind: None,
+        }];
+        assert!(patterns[0].validate().is_ok());
+        let email = mail("no-reply@auth.example.com", "code 123456", "ref ABCDEF");
+        let code = extract(&email, &patterns, None).unwrap();
+        assert_eq!(code.value, "ABCDEF");
+        let other = mail("no-reply@other.org", "code 123456", "ref ABCDEF");
+        assert_eq!(extract(&other, &patterns, None).unwrap().value, "123456");
+    }
+
+    #[test]
+    fn test_sender_matches() {
+        assert!(sender_matches("example.com", "a@example.com"));
+        assert!(sender_matches("example.com", "a@mail.Example.com"));
+        assert!(!sender_matches("example.com", "a@badexample.com"));
+        assert!(sender_matches("a@example.com", "A@example.com"));
+        assert!(!sender_matches("a@example.com", "b@example.com"));
+    }
+}
+
+mod test_mail_filter {
+    use crate::email::{Email, MailFilter, Watermark};
+    use chrono::{DateTime, Utc};
+    use regex::Regex;
+
+    #[test]
+    fn test_query_and_match() {
+        let filter = MailFilter {
+            from: Some("shop.example".to_string()),
+            subject: Some("Welcome".to_string()),
+            body: Some(Regex::new(r"code \d{6}").unwrap()),
+            to: Some("abc@duck.com".to_string()),
+            after: Some(Watermark {
+                uid_validity: 1,
+                uid: 41,
+            }),
+            since: None,
+        };
+        assert_eq!(
+            filter.query().unwrap(),
+            r#"FROM "shop.example" SUBJECT "Welcome" TO "abc@duck.com" UID 42:*"#
+        );
+        let mut email = Email {
+            from: "Shop <hi@shop.example>".to_string(),
+            subject: "welcome aboard".to_string(),
+            body: "your code 123456".to_string(),
+            to: vec!["me@example.com".to_string(), "abc@duck.com".to_string()],
+            uid: Some(42),
+            ..Default::default()
+        };
+        assert!(filter.matches(&email));
+        email.to.pop();
+        assert!(!filter.matches(&email));
+        email.to.push("ABC@duck.com".to_string());
+        assert!(filter.matches(&email));
+        email.uid = Some(41);
+        assert!(!filter.matches(&email));
+        assert_eq!(MailFilter::default().query().unwrap(), "ALL");
+        let quoted = MailFilter {
+            subject: Some("say \"hi\"".to_string()),
+            ..Default::default()
+        };
+        assert!(quoted.query().is_err());
+    }
+
+    #[test]
+    fn test_since_rejects_older_mail() {
+        let requested = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let filter = MailFilter {
+            since: Some(requested),
+            ..Default::default()
+        };
+        assert_eq!(filter.query().unwrap(), "SINCE 01-May-2024");
+        let mut email = Email {
+            received: Some(requested - chrono::Duration::hours(1)),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&email));
+        email.received = Some(requested + chrono::Duration::seconds(5));
+        assert!(filter.matches(&email));
+    }
+}
+
+mod test_wait_until {
+    use crate::email::{poll_until, CancelledError, Email, EmailConfig, TimeoutError, WaitOptions};
+    use std::cell::RefCell;
+    use std::error::Error;
+    use std::future::ready;
+    use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+
+    fn email(uid: u32, subject: &str) -> Email {
+        Email {
+            uid: Some(uid),
+            subject: subject.to_string(),
+            ..Default::default()
+        }
+    }
+
+    type Found<T> = std::future::Ready<Result<T, Box<dyn Error>>>;
+
+    /// Search giving the UIDs of `checks` in turn, then the last one forever
+    fn search(checks: Vec<Vec<u32>>) -> impl FnMut() -> Found<Vec<u32>> {
+        let mut checks = checks.into_iter();
+        let mut last = vec![];
+        move || {
+            if let Some(check) = checks.next() {
+                last = check;
+            }
+            ready(Ok(last.clone()))
+        }
+    }
+
+    /// Fetch of the messages of `mailbox`, recording the UIDs asked for in `fetched`
+    fn fetch<'a>(
+        mailbox: &'a [(u32, &'a str)],
+        fetched: &'a RefCell<Vec<u32>>,
+    ) -> impl FnMut(Vec<u32>) -> Found<Vec<Email>> + 'a {
+        move |uids| {
+            fetched.borrow_mut().extend(&uids);
+            let emails = mailbox
+                .iter()
+                .filter(|(uid, _)| uids.contains(uid))
+                .map(|(uid, s)| email(*uid, s))
+                .collect();
+            ready(Ok(emails))
+        }
+    }
+
+    fn fast() -> WaitOptions<'static> {
+        WaitOptions {
+            timeout: Duration::from_secs(5),
+            period: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_each_message_is_checked_once() {
+        let seen = RefCell::new(vec![]);
+        let fetched = RefCell::new(vec![]);
+        let mailbox = [(1, "news"), (2, "welcome"), (3, "your code")];
+        let found = poll_until(
+            search(vec![vec![2, 1], vec![1, 2, 3]]),
+            fetch(&mailbox, &fetched),
+            |e| {
+                seen.borrow_mut().push(e.uid.unwrap());
+                ready(e.subject.contains("code"))
+            },
+            fast(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(found.uid, Some(3));
+        // oldest first, and 1 and 2 are neither downloaded nor given again on the second check
+        assert_eq!(seen.into_inner(), vec![1, 2, 3]);
+        assert_eq!(fetched.into_inner(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_newest_first_skips_deleted() {
+        let fetched = RefCell::new(vec![]);
+        // 4 is deleted between the search and the fetch
+        let mailbox = [(1, "code"), (2, "news"), (3, "code")];
+        let options = WaitOptions {
+            newest_first: true,
+            ..fast()
+        };
+        let found = poll_until(
+            search(vec![vec![1, 2, 3, 4]]),
+            fetch(&mailbox, &fetched),
+            |e| ready(e.subject == "code"),
+            options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(found.uid, Some(3));
+        assert_eq!(fetched.into_inner(), vec![4, 3, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_progress() {
+        let reports = RefCell::new(vec![]);
+        let fetched = RefCell::new(vec![]);
+        let options = WaitOptions {
+            progress: Some(Box::new(|p| {
+                reports.borrow_mut().push((p.attempt, p.checked))
+            })),
+            ..fast()
+        };
+        let mailbox = [(1, "news"), (2, "code")];
+        poll_until(
+            search(vec![vec![1], vec![1], vec![2]]),
+            fetch(&mailbox, &fetched),
+            |e| ready(e.subject == "code"),
+            options,
+        )
+        .await
+        .unwrap();
+        // reported after each check without a match
+        assert_eq!(reports.into_inner(), vec![(1, 1), (2, 0)]);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let attempts = RefCell::new(0);
+        let fetched = RefCell::new(vec![]);
+        let options = WaitOptions {
+            timeout: Duration::from_millis(20),
+            period: Duration::from_millis(5),
+            progress: Some(Box::new(|p| *attempts.borrow_mut() = p.attempt)),
+            ..Default::default()
+        };
+        let err = poll_until(
+            search(vec![vec![1]]),
+            fetch(&[(1, "news")], &fetched),
+            |_| ready(false),
+            options,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.is::<TimeoutError>());
+        assert!(attempts.into_inner() > 1);
+        assert_eq!(fetched.into_inner(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_search_error_stops_the_wait() {
+        let fetched = RefCell::new(vec![]);
+        let err = poll_until(
+            || ready(Err("connection refused".into())),
+            fetch(&[], &fetched),
+            |_| ready(true),
+            fast(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "connection refused");
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_before_connecting() {
+        let account = EmailConfig::new(
+            "me@example.com".to_string(),
+            "pwd".to_string(),
+            "imap.invalid:993".to_string(),
+        );
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let options = WaitOptions {
+            cancel: Some(cancel),
+            ..Default::default()
+        };
+        let err = account
+            .wait_until("ALL", |_| std::future::ready(true), options)
+            .await
+            .unwrap_err();
+        assert!(err.is::<CancelledError>());
+    }
+}
+
+mod test_fetch_query {
+    use crate::email::FetchQuery;
+    use crate::mailbox::parse_sort;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_fetch_query() {
+        assert_eq!(FetchQuery::default().to_imap(), "ALL");
+        let query = FetchQuery {
+            unseen: true,
+            since: NaiveDate::from_ymd_opt(2024, 3, 1),
+            before: NaiveDate::from_ymd_opt(2024, 4, 15),
+        };
+        assert_eq!(
+            query.to_imap(),
+            "UNSEEN SINCE 01-Mar-2024 BEFORE 15-Apr-2024"
+        );
+    }
+
+    #[test]
+    fn test_parse_sort() {
+        assert_eq!(parse_sort(b"* SORT 5 3 17\r\n"), vec![5, 3, 17]);
+        assert_eq!(parse_sort(b"* SORT\r\n"), Vec::<u32>::new());
+        assert_eq!(
+            parse_sort(b"* CAPABILITY IMAP4rev1\r\n* SORT 2\r\n"),
+            vec![2]
+        );
+    }
+}
+
+mod test_list {
+    use crate::mailbox::{decode_words, flag_name, uid_set};
+    use imap::types::Flag;
+
+    #[test]
+    fn test_uid_set() {
+        assert_eq!(uid_set(&[]), "");
+        assert_eq!(uid_set(&[7]), "7");
+        assert_eq!(uid_set(&[9, 1, 2, 3, 7, 10, 3]), "1:3,7,9:10");
+        let uids: Vec<u32> = (1..=5000).collect();
+        assert_eq!(uid_set(&uids), "1:5000");
+    }
+
+    #[test]
+    fn test_decode_words() {
+        assert_eq!(decode_words(b"=?utf-8?q?caf=C3=A9?="), "caf\u{e9}");
+        assert_eq!(decode_words(b"=?UTF-8?B?SGVsbG8=?= world"), "Hello world");
+        assert_eq!(decode_words(b"plain subject"), "plain subject");
+    }
+
+    #[test]
+    fn test_flag_name() {
+        assert_eq!(flag_name(&Flag::Seen), "seen");
+        assert_eq!(flag_name(&Flag::Flagged), "flagged");
+        assert_eq!(flag_name(&Flag::MayCreate), "*");
+        assert_eq!(flag_name(&Flag::Custom("$Forwarded".into())), "$Forwarded");
+        assert_eq!(flag_name(&Flag::Custom("\\Junk".into())), "Junk");
+    }
+}
+
+mod test_html_to_text {
+    use crate::email::html_to_text;
+
+    #[test]
+    fn test_html_to_text() {
+        let html = "<html><head><style>p { color: red }</style></head><body>\
+            <p>Hello&nbsp;<b>Bob</b>,</p><p>Your code is <i>482913</i>.<br>Tom &amp; Jerry</p>\
+            <script>alert(1)</script></body></html>";
+        assert_eq!(
+            html_to_text(html),
+            "Hello Bob,\nYour code is 482913.\nTom & Jerry"
+        );
+    }
+}
+
+mod test_view {
+    use crate::email::leaf_parts;
+    use crate::view::{human_size, is_attachment, part, part_name, render_message, truncate};
+
+    /// alternative plain and html bodies, then a PDF attachment
+    const MULTIPART: &[u8] = b"From: Shop <hello@shop.example>\r
+To: me@example.com\r
+Subject: Your order\r
+MIME-Version: 1.0\r
+Content-Type: multipart/mixed; boundary=\"outer\"\r
+\r
+--outer\r
+Content-Type: multipart/alternative; boundary=\"inner\"\r
+\r
+--inner\r
+Content-Type: text/plain; charset=utf-8\r
+\r
+Thanks for your order.\r
+--inner\r
+Content-Type: text/html; charset=utf-8\r
+\r
+<p>Thanks for your <b>order</b>.</p>\r
+--inner--\r
+--outer\r
+Content-Type: application/pdf; name=\"invoice.pdf\"\r
+Content-Disposition: attachment; filename=\"invoice.pdf\"\r
+Content-Transfer-Encoding: base64\r
+\r
+JVBERi0xLjQK\r
+--outer--\r
+";
+
+    const HTML_ONLY: &[u8] = b"From: hello@shop.example\r
+Subject: News\r
+Content-Type: multipart/mixed; boundary=\"b\"\r
+\r
+--b\r
+Content-Type: text/plain\r
+Content-Disposition: attachment; filename=\"notes.txt\"\r
+\r
+not the body\r
+--b\r
+Content-Type: text/html\r
+\r
+<p>Big <i>sale</i></p>\r
+--b--\r
+";
+
+    #[test]
+    fn test_leaf_parts_numbering() {
+        let mail = mailparse::parse_mail(MULTIPART).unwrap();
+        let parts = leaf_parts(&mail);
+        let types: Vec<&str> = parts.iter().map(|p| p.ctype.mimetype.as_str()).collect();
+        assert_eq!(types, ["text/plain", "text/html", "application/pdf"]);
+        assert!(is_attachment(parts[2]));
+        assert!(!is_attachment(parts[0]));
+        assert_eq!(part_name(parts[2]).as_deref(), Some("invoice.pdf"));
+
+        let single = mailparse::parse_mail(b"Subject: hi\r\n\r\nhello").unwrap();
+        assert_eq!(leaf_parts(&single).len(), 1);
+    }
+
+    #[test]
+    fn test_part_bounds() {
+        let mail = mailparse::parse_mail(MULTIPART).unwrap();
+        assert_eq!(part(&mail, 1).unwrap().ctype.mimetype, "text/plain");
+        assert_eq!(
+            part(&mail, 3).unwrap().get_body_raw().unwrap(),
+            b"%PDF-1.4\n"
+        );
+        assert_eq!(part(&mail, 0).unwrap_err(), "No part 0, the message has 3");
+        assert!(part(&mail, 4).is_err());
+    }
+
+    #[test]
+    fn test_render_plain_body_and_parts() {
+        let mail = mailparse::parse_mail(MULTIPART).unwrap();
+        let out = render_message(&mail).unwrap();
+        assert!(out.contains("Shop <hello@shop.example>"), "{}", out);
+        assert!(out.contains("Your order"), "{}", out);
+        assert!(out.contains("Thanks for your order."), "{}", out);
+        assert!(!out.contains("<b>"), "{}", out);
+        assert!(out.contains("[1] text/plain "), "{}", out);
+        assert!(out.contains("[2] text/html "), "{}", out);
+        assert!(
+            out.contains("[3] application/pdf invoice.pdf 9B"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn test_render_html_fallback_skips_attachments() {
+        let mail = mailparse::parse_mail(HTML_ONLY).unwrap();
+        let out = render_message(&mail).unwrap();
+        assert!(out.contains("Big sale"), "{}", out);
+        assert!(!out.contains("not the body"), "{}", out);
+        assert!(out.contains("[1] text/plain notes.txt "), "{}", out);
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(0), "0B");
+        assert_eq!(human_size(512), "512B");
+        assert_eq!(human_size(12 * 1024), "12K");
+        assert_eq!(human_size(1572864), "1.5M");
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("line\r\nbreak", 20), "line  break");
+        assert_eq!(truncate("caf\u{e9} au lait", 5), "caf\u{e9}\u{2026}");
+        assert_eq!(truncate("abc", 0), "\u{2026}");
+    }
+}
+
+mod test_template {
+    use crate::email::Email;
+    use crate::template::Template;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_render() {
+        let email = Email {
+            from: "alice@example.com".to_string(),
+            subject: "Your invoice for March".to_string(),
+            to: vec!["me@example.com".to_string(), "you@example.com".to_string()],
+            headers: vec![("List-Id".to_string(), "news".to_string())],
+            uid: Some(42),
+            ..Default::default()
+        };
+        let template: Template =
+            "{uid} {{{from.addr}}} {subject:12}| {to} [{header.list-id}]{date}"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            template.render(&email),
+            "42 {alice@example.com} Your invoice| me@example.com, you@example.com [news]"
+        );
+    }
+
+    #[test]
+    fn test_render_dates_and_headers() {
+        let date = Utc.with_ymd_and_hms(2024, 3, 1, 9, 30, 0).unwrap();
+        let email = Email {
+            from: "Alice Martin <alice@example.com>".to_string(),
+            headers: vec![
+                (
+                    "From".to_string(),
+                    "Alice Martin <alice@example.com>".to_string(),
+                ),
+                ("X-Mailer".to_string(), "shop".to_string()),
+            ],
+            date: Some(date),
+            received: Some(date + chrono::Duration::minutes(2)),
+            ..Default::default()
+        };
+        let template: Template = "{date} | {received:%H:%M:%S %z} | {from.name}"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            template.render(&email),
+            "2024-03-01 09:30 | 09:32:00 +0000 | Alice Martin"
+        );
+        let headers: Template = "{headers}".parse().unwrap();
+        assert_eq!(
+            headers.render(&email),
+            "From: Alice Martin <alice@example.com>\nX-Mailer: shop\n"
+        );
+        let undated: Template = "[{date}] [{from.name}]".parse().unwrap();
+        assert_eq!(undated.render(&Email::default()), "[] []");
+    }
+
+    #[test]
+    fn test_invalid() {
+        for bad in ["{nope}", "{subject", "a } b", "{subject:wide}", "{header.}"] {
+            assert!(bad.parse::<Template>().is_err(), "{}", bad);
+        }
+        assert!("{date:%Y-%m-%d %H:%M} {received:%s}"
+            .parse::<Template>()
+            .is_ok());
+    }
+}
+
+mod test_protocol {
+    use crate::protocol::{redact_header, redact_imap, redact_url, Traced};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact_url("https://quack.duckduckgo.com/api/auth/login?user=me&otp=a-b-c"),
+            "https://quack.duckduckgo.com/api/auth/login?user=me&otp=***"
+        );
+        assert_eq!(
+            redact_url("https://addy.io/api/v1/aliases"),
+            "https://addy.io/api/v1/aliases"
+        );
+        assert_eq!(redact_header("Authorization", "Bearer abc"), "***");
+        assert_eq!(redact_header("Authentication", "key"), "***");
+        assert_eq!(redact_header("user-agent", "ms"), "ms");
+    }
+
+    #[test]
+    fn test_redact_imap() {
+        assert_eq!(
+            redact_imap("a1 LOGIN me@example.com \"hunter 2\""),
+            "a1 LOGIN me@example.com ***"
+        );
+        assert_eq!(redact_imap("a2 login me pwd"), "a2 login me ***");
+        assert_eq!(redact_imap("a3 UID SEARCH UNSEEN"), "a3 UID SEARCH UNSEEN");
+    }
+
+    #[test]
+    fn test_traced_passes_bytes_through() {
+        let mut stream = Traced::new(std::io::Cursor::new(b"* OK ready\r\npartial".to_vec()));
+        let mut read = String::new();
+        stream.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "* OK ready\r\npartial");
+        let mut written = Traced::new(vec![]);
+        written.write_all(b"a1 NOOP\r\n").unwrap();
+        written.flush().unwrap();
+    }
+}
+
+mod test_output {
+    use crate::utils::output::{log_level, ColorChoice};
+
+    #[test]
+    fn test_color_choice() {
+        assert!(ColorChoice::Always.enabled(false));
+        assert!(!ColorChoice::Never.enabled(true));
+        assert!(!ColorChoice::Auto.enabled(false));
+        std::env::set_var("NO_COLOR", "");
+        assert!(ColorChoice::Auto.enabled(true));
+        std::env::set_var("NO_COLOR", "1");
+        assert!(!ColorChoice::Auto.enabled(true));
+        assert!(ColorChoice::Always.enabled(true));
+        std::env::remove_var("NO_COLOR");
+        assert!(ColorChoice::Auto.enabled(true));
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(log_level(0, 0), "info");
+        assert_eq!(log_level(1, 0), "debug");
+        assert_eq!(log_level(2, 0), "trace");
+        assert_eq!(log_level(0, 1), "warn");
+        assert_eq!(log_level(0, 2), "error");
+        assert_eq!(log_level(0, 3), "off");
+        assert_eq!(log_level(2, 1), "debug");
+        assert_eq!(log_level(u8::MAX, 0), "trace");
+        assert_eq!(log_level(0, u8::MAX), "off");
+    }
+}
+
+mod test_stream {
+    use crate::email::{message_stream, Email, MessageSource, StreamOptions};
+    use crate::mailbox::BlockingResult;
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+
+    /// Messages with UIDs 1 to `count`, received in UID order
+    struct FakeSource {
+        count: u32,
+        batches: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl MessageSource for FakeSource {
+        fn sorted_uids(&mut self, _query: &str, newest_first: bool) -> BlockingResult<Vec<u32>> {
+            let mut uids: Vec<u32> = (1..=self.count).collect();
+            if newest_first {
+                uids.reverse();
+            }
+            Ok(uids)
+        }
+
+        fn fetch_uids(&mut self, uids: &[u32], _peek: bool) -> BlockingResult<Vec<Email>> {
+            self.batches.lock().unwrap().push(uids.len());
+            // answer in another order, as servers may
+            Ok(uids
+                .iter()
+                .rev()
+                .map(|&uid| Email {
+                    uid: Some(uid),
+                    ..Default::default()
+                })
+                .collect())
+        }
+    }
+
+    fn source(count: u32) -> (FakeSource, Arc<Mutex<Vec<usize>>>) {
+        let batches = Arc::new(Mutex::new(vec![]));
+        let source = FakeSource {
+            count,
+            batches: batches.clone(),
+        };
+        (source, batches)
+    }
+
+    #[tokio::test]
+    async fn test_sorted_in_batches() {
+        let (source, batches) = source(120);
+        let options = StreamOptions {
+            newest_first: true,
+            limit: Some(70),
+            ..Default::default()
+        };
+        let uids: Vec<u32> = message_stream(move || source, |_| {}, "ALL", options)
+            .map(|email| email.unwrap().uid.unwrap())
+            .collect()
+            .await;
+        assert_eq!(uids, (51..=120).rev().collect::<Vec<_>>());
+        assert_eq!(*batches.lock().unwrap(), vec![50, 20]);
+    }
+
+    #[tokio::test]
+    async fn test_progress() {
+        let (source, _) = source(3);
+        let mut seen = vec![];
+        let options = StreamOptions {
+            progress: Some(Box::new(|p| seen.push((p.fetched, p.total)))),
+            ..Default::default()
+        };
+        let count = message_stream(move || source, |_| {}, "ALL", options)
+            .count()
+            .await;
+        assert_eq!(count, 3);
+        assert_eq!(seen, vec![(1, Some(3)), (2, Some(3)), (3, Some(3))]);
+    }
+
+    #[test]
+    fn test_nothing_runs_before_polling() {
+        // no runtime here, building the stream must not spawn anything
+        let opened = Arc::new(AtomicBool::new(false));
+        let flag = opened.clone();
+        let stream = message_stream(
+            move || {
+                flag.store(true, Ordering::SeqCst);
+                source(1).0
+            },
+            |_| {},
+            "ALL",
+            StreamOptions::default(),
+        );
+        drop(stream);
+        assert!(!opened.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_dropping_stops_the_worker() {
+        let (source, batches) = source(500);
+        let (closed_tx, closed) = mpsc::channel();
+        let mut stream = Box::pin(message_stream(
+            move || source,
+            move |_| closed_tx.send(()).unwrap(),
+            "ALL",
+            StreamOptions::default(),
+        ));
+        assert_eq!(stream.next().await.unwrap().unwrap().uid, Some(1));
+        drop(stream);
+        closed.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(*batches.lock().unwrap(), vec![50]);
+    }
+}
+
+mod test_mailbox {
+    use crate::email::EmailConfig;
+    use crate::mailbox::{is_disconnect, key, retry_once, BlockingResult, Mailbox, Pool};
+    use std::error::Error;
+
+    fn account(pwd: &str) -> EmailConfig {
+        EmailConfig {
+            email: "me@example.com".to_string(),
+            pwd: pwd.to_string(),
+            smtp: "imap.example.com:993".to_string(),
+        }
+    }
+
+    fn lost() -> Box<dyn Error + Send + Sync> {
+        Box::new(imap::error::Error::ConnectionLost)
+    }
+
+    #[test]
+    fn test_is_disconnect() {
+        let io = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "broken pipe");
+        assert!(is_disconnect(&imap::error::Error::Io(io)));
+        assert!(is_disconnect(&imap::error::Error::ConnectionLost));
+        assert!(!is_disconnect(&imap::error::Error::No("no".to_string())));
+        assert!(!is_disconnect(&imap::error::Error::Bad("bad".to_string())));
+        let other: Box<dyn Error> = "No message content".into();
+        assert!(!is_disconnect(other.as_ref()));
+    }
+
+    #[test]
+    fn test_retry_once_after_disconnect() {
+        let mut attempts = 0;
+        let result = retry_once(|| {
+            attempts += 1;
+            if attempts == 1 {
+                Err(lost())
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[test]
+    fn test_retry_only_once() {
+        let mut attempts = 0;
+        let result: BlockingResult<()> = retry_once(|| {
+            attempts += 1;
+            Err(lost())
+        });
+        assert!(is_disconnect(result.unwrap_err().as_ref()));
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn test_no_retry_on_other_errors() {
+        let mut attempts = 0;
+        let result: BlockingResult<()> = retry_once(|| {
+            attempts += 1;
+            Err("No message content".into())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_put_back_drops_disconnected() {
+        let pool = Pool::new();
+        let mailbox = pool.take(&account("secret"));
+        assert!(!mailbox.is_connected());
+        pool.put_back(mailbox);
+        assert_eq!(pool.idle(&account("secret")), 0);
+        pool.put_back(Mailbox::new(account("secret")));
+        assert_eq!(pool.idle(&account("secret")), 0);
+    }
+
+    #[test]
+    fn test_key_depends_on_password() {
+        assert_eq!(key(&account("secret")), key(&account("secret")));
+        assert_ne!(key(&account("secret")), key(&account("changed")));
+        assert!(!key(&account("secret")).contains("secret"));
+    }
+}
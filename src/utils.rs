@@ -23,7 +23,45 @@ pub mod browser {
 
     #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "linux")))]
     pub fn open(_url: &str) -> std::io::Result<()> {
-        println!("Invalid operator");
-        Ok(())
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "cannot open a browser on this platform",
+        ))
+    }
+}
+
+pub mod output {
+    //! utils of terminal output and logging
+
+    /// When to colour the output
+    #[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ColorChoice {
+        Auto,
+        Always,
+        Never,
+    }
+
+    impl ColorChoice {
+        /// Whether to colour a stream, `terminal` telling whether it is one.
+        /// An empty `NO_COLOR` is ignored, as the convention asks.
+        pub fn enabled(self, terminal: bool) -> bool {
+            match self {
+                Self::Always => true,
+                Self::Never => false,
+                Self::Auto => terminal && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+            }
+        }
+    }
+
+    /// Log level of `-v` and `-q` given `verbose` and `quiet` times, info by default.
+    pub fn log_level(verbose: u8, quiet: u8) -> &'static str {
+        match i16::from(verbose).saturating_sub(i16::from(quiet)) {
+            i16::MIN..=-3 => "off",
+            -2 => "error",
+            -1 => "warn",
+            0 => "info",
+            1 => "debug",
+            _ => "trace",
+        }
     }
 }
@@ -0,0 +1,95 @@
+//! Views of a message for `show`
+use crate::email::{html_to_text, leaf_parts};
+use colored::*;
+use mailparse::{DispositionType, MailHeaderMap, ParsedMail};
+use std::error::Error;
+
+/// Size in bytes, kibibytes or mebibytes, e.g. `512B`, `12K` or `1.5M`.
+pub fn human_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{}B", bytes),
+        1024..=1048575 => format!("{:.0}K", bytes as f64 / 1024.0),
+        _ => format!("{:.1}M", bytes as f64 / 1048576.0),
+    }
+}
+
+/// Cut `text` to `width` characters, marking the cut with `…`.
+pub fn truncate(text: &str, width: usize) -> String {
+    let text = text.replace(['\r', '\n'], " ");
+    if text.chars().count() <= width {
+        return text;
+    }
+    let mut cut: String = text.chars().take(width.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
+
+/// File name of a part, from its disposition or content type.
+pub fn part_name(part: &ParsedMail) -> Option<String> {
+    part.get_content_disposition()
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .cloned()
+}
+
+pub fn is_attachment(part: &ParsedMail) -> bool {
+    matches!(
+        part.get_content_disposition().disposition,
+        DispositionType::Attachment
+    )
+}
+
+/// Part `n` of [`leaf_parts`], numbered from 1.
+pub fn part<'a, 'b>(mail: &'b ParsedMail<'a>, n: usize) -> Result<&'b ParsedMail<'a>, String> {
+    let parts = leaf_parts(mail);
+    n.checked_sub(1)
+        .and_then(|i| parts.get(i).copied())
+        .ok_or_else(|| format!("No part {}, the message has {}", n, parts.len()))
+}
+
+/// Headers, text body and list of parts of a message.
+/// The body is the first text/plain part that is not an attachment,
+/// else the text of the first such text/html part.
+pub fn render_message(mail: &ParsedMail) -> Result<String, Box<dyn Error>> {
+    let mut out = String::new();
+    let headers = mail.get_headers();
+    for key in ["From", "To", "Cc", "Date", "Subject"] {
+        if let Some(value) = headers.get_first_value(key) {
+            out += &format!(
+                "{} {}\n",
+                format!("{:<8}", format!("{}:", key)).bold(),
+                value
+            );
+        }
+    }
+    out.push('\n');
+    let parts = leaf_parts(mail);
+    let text = |mimetype: &str| {
+        parts
+            .iter()
+            .find(|p| p.ctype.mimetype.eq_ignore_ascii_case(mimetype) && !is_attachment(p))
+    };
+    if let Some(plain) = text("text/plain") {
+        out += plain.get_body()?.trim_end();
+    } else if let Some(html) = text("text/html") {
+        out += &html_to_text(&html.get_body()?);
+    } else {
+        out += &"(no text part)".dimmed().to_string();
+    }
+    out.push('\n');
+    if parts.len() > 1 {
+        out += &format!("\n{}\n", "Parts:".bold());
+        for (i, part) in parts.iter().enumerate() {
+            let size = part.get_body_raw().map(|b| b.len()).unwrap_or_default();
+            out += &format!(
+                "  [{}] {} {}{}\n",
+                i + 1,
+                part.ctype.mimetype,
+                part_name(part).map(|n| n + " ").unwrap_or_default(),
+                human_size(size as u64)
+            );
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,243 @@
+//! Duckduckgo email protection against a local mock of its API
+#![cfg(feature = "ddep")]
+use mail_sitter::ddep::{AccountStatus, Client, DdConfig, DdgError};
+use mail_sitter::providers::AliasProvider;
+use serde_json::json;
+use std::time::Duration;
+use wiremock::matchers::{header, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// Mock of the whole login flow for user `me` and passphrase `a b c`,
+/// expecting `otp_requests` requests for the passphrase email.
+async fn mock_api(otp_requests: u64) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/auth/loginlink"))
+        .and(query_param("user", "me"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(otp_requests)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/auth/login"))
+        .and(query_param("user", "me"))
+        .and(query_param("otp", "a-b-c"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"token": "tok"})))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/email/dashboard"))
+        .and(header("Authorization", "Bearer tok"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "stats": {"addresses_generated": 3},
+            "user": {"access_token": "at", "email": "real@example.com"}
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/email/addresses"))
+        .and(header("Authorization", "Bearer at"))
+        .respond_with(ResponseTemplate::new(201).set_body_json(json!({"address": "abc"})))
+        .mount(&server)
+        .await;
+    server
+}
+
+#[tokio::test]
+async fn test_otp_login_dashboard_alias() {
+    let server = mock_api(1).await;
+    let mut client = Client::new("me".to_string(), None, None, Some(server.uri()));
+
+    assert!(client.otp(None).await.unwrap());
+    assert!(client.full_login("a b c", None).await.unwrap());
+    assert_eq!(client.token.as_deref(), Some("tok"));
+    assert_eq!(client.access_token.as_deref(), Some("at"));
+    assert_eq!(client.generated_addresses, Some(3));
+    assert_eq!(client.generate_alias().await.unwrap(), "abc");
+}
+
+#[tokio::test]
+async fn test_login_with_magic_link() {
+    let server = mock_api(1).await;
+    let mut client = Client::new("me".to_string(), None, None, Some(server.uri()));
+    client.otp(None).await.unwrap();
+    let link = "https://duckduckgo.com/email/login?otp=a-b-c&user=me";
+    assert_eq!(client.login(link, None).await.unwrap(), "tok");
+}
+
+#[tokio::test]
+async fn test_base_url_survives_config_round_trip() {
+    let server = mock_api(1).await;
+    let mut client = Client::new("me".to_string(), None, None, Some(server.uri()));
+    client.otp(None).await.unwrap();
+    client.full_login("a b c", None).await.unwrap();
+
+    let cfg: DdConfig = client.into();
+    assert_eq!(cfg.base_url.as_deref(), Some(server.uri().as_str()));
+    let client: Client = cfg.into();
+    assert_eq!(client.generate().await.unwrap(), "abc@duck.com");
+    let status = client.status().await.unwrap();
+    assert_eq!(status.forwarding.as_deref(), Some("real@example.com"));
+    assert_eq!(status.aliases, Some(3));
+}
+
+#[tokio::test]
+async fn test_rejected_otp_request_is_an_error() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/auth/loginlink"))
+        .respond_with(ResponseTemplate::new(400))
+        .mount(&server)
+        .await;
+    let client = Client::new("me".to_string(), None, None, Some(server.uri()));
+    assert!(client.otp(None).await.is_err());
+}
+
+/// Client with tokens, against a server answering every alias request with `response`.
+async fn alias_response(response: ResponseTemplate) -> (MockServer, Client) {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/email/addresses"))
+        .respond_with(response)
+        .mount(&server)
+        .await;
+    let client = Client::new(
+        "me".to_string(),
+        Some("tok".to_string()),
+        Some("at".to_string()),
+        Some(server.uri()),
+    );
+    (server, client)
+}
+
+#[tokio::test]
+async fn test_expired_token_is_unauthorized() {
+    let (_server, client) = alias_response(ResponseTemplate::new(401)).await;
+    let err = client.generate_alias().await.unwrap_err();
+    assert!(matches!(err, DdgError::Unauthorized));
+    assert!(err.needs_login());
+}
+
+#[tokio::test]
+async fn test_rate_limit_reads_retry_after() {
+    let response = ResponseTemplate::new(429).insert_header("Retry-After", "7");
+    let (_server, client) = alias_response(response).await;
+    let err = client.generate_alias().await.unwrap_err();
+    assert!(matches!(
+        err,
+        DdgError::RateLimited {
+            retry_after: Some(_)
+        }
+    ));
+    assert_eq!(err.retry_delay(0), Some(Duration::from_secs(7)));
+}
+
+#[tokio::test]
+async fn test_malformed_alias_response() {
+    let response = ResponseTemplate::new(201).set_body_json(json!({"alias": "abc"}));
+    let (_server, client) = alias_response(response).await;
+    let err = client.generate_alias().await.unwrap_err();
+    assert!(matches!(err, DdgError::Malformed(_)));
+    assert!(err.retry_delay(0).is_none());
+}
+
+#[tokio::test]
+async fn test_rate_limit_reads_retry_after_date() {
+    let at = chrono::Utc::now() + chrono::Duration::seconds(30);
+    let date = at.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    let response = ResponseTemplate::new(429).insert_header("Retry-After", date.as_str());
+    let (_server, client) = alias_response(response).await;
+    let delay = client
+        .generate_alias()
+        .await
+        .unwrap_err()
+        .retry_delay(0)
+        .unwrap();
+    assert!(delay > Duration::from_secs(25) && delay <= Duration::from_secs(30));
+}
+
+#[tokio::test]
+async fn test_challenge_page_is_bot_challenge() {
+    let response = ResponseTemplate::new(200).set_body_raw(
+        "<html>Please solve the captcha</html>",
+        "text/html; charset=utf-8",
+    );
+    let (_server, client) = alias_response(response).await;
+    let err = client.generate_alias().await.unwrap_err();
+    assert!(matches!(err, DdgError::BotChallenge));
+}
+
+#[tokio::test]
+async fn test_json_mentioning_a_challenge_is_not_a_bot_challenge() {
+    let response =
+        ResponseTemplate::new(400).set_body_json(json!({"error": "invalid challenge response"}));
+    let (_server, client) = alias_response(response).await;
+    let err = client.generate_alias().await.unwrap_err();
+    assert!(matches!(err, DdgError::Status { .. }), "{:?}", err);
+
+    let response = ResponseTemplate::new(201).set_body_string("captcha");
+    let (_server, client) = alias_response(response).await;
+    let err = client.generate_alias().await.unwrap_err();
+    assert!(matches!(err, DdgError::Malformed(_)), "{:?}", err);
+}
+
+#[tokio::test]
+async fn test_validate_token_refreshes_access_token() {
+    let server = mock_api(0).await;
+    let mut client = Client::new(
+        "me".to_string(),
+        Some("tok".to_string()),
+        Some("stale".to_string()),
+        Some(server.uri()),
+    );
+    assert!(client.validate_token().await.unwrap());
+    assert_eq!(client.access_token.as_deref(), Some("at"));
+    assert_eq!(client.generate_alias().await.unwrap(), "abc");
+}
+
+#[tokio::test]
+async fn test_validate_expired_token() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/email/dashboard"))
+        .respond_with(ResponseTemplate::new(401))
+        .mount(&server)
+        .await;
+    let mut client = Client::new(
+        "me".to_string(),
+        Some("expired".to_string()),
+        Some("at".to_string()),
+        Some(server.uri()),
+    );
+    assert!(!client.validate_token().await.unwrap());
+    let mut client = Client::new("me".to_string(), None, None, Some(server.uri()));
+    assert!(!client.validate_token().await.unwrap());
+}
+
+#[tokio::test]
+async fn test_account_status() {
+    let server = mock_api(0).await;
+    let client = Client::new(
+        "me".to_string(),
+        Some("tok".to_string()),
+        None,
+        Some(server.uri()),
+    );
+    let dashboard = client.dashboard().await.unwrap();
+    assert_eq!(dashboard.user.access_token, "at");
+    let status = client.account_status().await.unwrap();
+    assert_eq!(
+        status,
+        AccountStatus {
+            username: "me".to_string(),
+            forwarding: Some("real@example.com".to_string()),
+            aliases: Some(3),
+            token_valid: true,
+        }
+    );
+
+    let client = Client::new("me".to_string(), None, None, Some(server.uri()));
+    let status = client.account_status().await.unwrap();
+    assert!(!status.token_valid);
+    assert_eq!(status.aliases, None);
+}
@@ -0,0 +1,236 @@
+//! Alias providers against a local mock of their HTTP API
+#![cfg(any(feature = "simplelogin", feature = "addy", feature = "relay"))]
+use mail_sitter::providers::AliasProvider;
+use serde_json::json;
+use wiremock::matchers::{header, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+#[cfg(feature = "simplelogin")]
+mod simplelogin {
+    use super::*;
+    use mail_sitter::providers::simplelogin::Client;
+
+    #[tokio::test]
+    async fn test_login_generate_list_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/user_info"))
+            .and(header("Authentication", "key"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({"email": "me@example.com", "is_premium": true})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/alias/random/new"))
+            .respond_with(
+                ResponseTemplate::new(201).set_body_json(json!({"email": "x1@simplelogin.com"})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v2/aliases"))
+            .and(query_param("page_id", "0"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                json!({"aliases": [{"email": "x1@simplelogin.com"}, {"email": "x2@simplelogin.com"}]}),
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v2/aliases"))
+            .and(query_param("page_id", "1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"aliases": []})))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/stats"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"nb_alias": 2})))
+            .mount(&server)
+            .await;
+
+        let mut client = Client::new(String::new(), None, Some(server.uri()));
+        client.login("key").await.unwrap();
+        assert_eq!(client.account(), "me@example.com");
+        assert_eq!(client.generate().await.unwrap(), "x1@simplelogin.com");
+        assert_eq!(client.list().await.unwrap().len(), 2);
+        let status = client.status().await.unwrap();
+        assert_eq!(status.aliases, Some(2));
+        assert_eq!(status.premium, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_generated_alias_is_recorded() {
+        use mail_sitter::aliases::{AliasDetails, Registry};
+        use mail_sitter::Config;
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/alias/random/new"))
+            .respond_with(
+                ResponseTemplate::new(201).set_body_json(json!({"email": "x1@simplelogin.com"})),
+            )
+            .mount(&server)
+            .await;
+        let dir = std::env::temp_dir().join(format!("mailsitter-record-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let registry = dir.join("aliases.yaml");
+        let registry = registry.to_str().unwrap();
+
+        let client = Client::new(
+            "me@example.com".to_string(),
+            Some("key".to_string()),
+            Some(server.uri()),
+        );
+        let details = AliasDetails {
+            label: Some("shop".to_string()),
+            ..Default::default()
+        };
+        let alias = client.generate_recorded(registry, details).await.unwrap();
+        assert_eq!(alias.address, "x1@simplelogin.com");
+        let recorded = Registry::read(registry).unwrap();
+        let recorded = recorded.get("x1@simplelogin.com").unwrap();
+        assert_eq!(recorded.provider, "simplelogin");
+        assert_eq!(recorded.account, "me@example.com");
+        assert_eq!(recorded.label.as_deref(), Some("shop"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bad_key_fails_login() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/user_info"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+        let mut client = Client::new(String::new(), None, Some(server.uri()));
+        assert!(client.login("bad").await.is_err());
+    }
+}
+
+#[cfg(feature = "addy")]
+mod addy {
+    use super::*;
+    use mail_sitter::providers::addy::Client;
+
+    #[tokio::test]
+    async fn test_login_generate_list_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/account-details"))
+            .and(header("Authorization", "Bearer key"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({"data": {"username": "me", "subscription": "free"}})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/aliases"))
+            .respond_with(
+                ResponseTemplate::new(201)
+                    .set_body_json(json!({"data": {"email": "a1@anonaddy.me"}})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/aliases"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({"data": [{"email": "a1@anonaddy.me"}]})),
+            )
+            .mount(&server)
+            .await;
+
+        let mut client = Client::new(String::new(), None, Some(server.uri()));
+        client.login("key").await.unwrap();
+        assert_eq!(client.account(), "me");
+        assert_eq!(client.generate().await.unwrap(), "a1@anonaddy.me");
+        assert_eq!(client.list().await.unwrap(), vec!["a1@anonaddy.me"]);
+        let status = client.status().await.unwrap();
+        assert_eq!(status.aliases, Some(1));
+        assert_eq!(status.premium, Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_list_follows_pages() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/aliases"))
+            .and(query_param("page[number]", "1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [{"email": "a1@anonaddy.me"}, {"email": "a2@anonaddy.me"}],
+                "meta": {"current_page": 1, "last_page": 2}
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/aliases"))
+            .and(query_param("page[number]", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [{"email": "a3@anonaddy.me"}],
+                "meta": {"current_page": 2, "last_page": 2}
+            })))
+            .mount(&server)
+            .await;
+
+        let client = Client::new(
+            "me".to_string(),
+            Some("key".to_string()),
+            Some(server.uri()),
+        );
+        assert_eq!(
+            client.list().await.unwrap(),
+            vec!["a1@anonaddy.me", "a2@anonaddy.me", "a3@anonaddy.me"]
+        );
+    }
+}
+
+#[cfg(feature = "relay")]
+mod relay {
+    use super::*;
+    use mail_sitter::providers::relay::Client;
+
+    #[tokio::test]
+    async fn test_login_generate_list_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/users/"))
+            .and(header("Authorization", "Token key"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!([{"email": "me@example.com"}])),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/profiles/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([{"has_premium": false}])))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/relayaddresses/"))
+            .respond_with(
+                ResponseTemplate::new(201).set_body_json(json!({"full_address": "r1@mozmail.com"})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/relayaddresses/"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!([{"full_address": "r1@mozmail.com"}])),
+            )
+            .mount(&server)
+            .await;
+
+        let mut client = Client::new(String::new(), None, Some(server.uri()));
+        client.login("key").await.unwrap();
+        assert_eq!(client.account(), "me@example.com");
+        assert_eq!(client.generate().await.unwrap(), "r1@mozmail.com");
+        assert_eq!(client.list().await.unwrap(), vec!["r1@mozmail.com"]);
+        let status = client.status().await.unwrap();
+        assert_eq!(status.forwarding.as_deref(), Some("me@example.com"));
+        assert_eq!(status.premium, Some(false));
+    }
+}